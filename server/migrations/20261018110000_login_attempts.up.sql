@@ -0,0 +1,10 @@
+-- Add up migration script here
+CREATE TABLE if not exists login_attempts (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    kind TEXT NOT NULL,
+    value TEXT NOT NULL,
+    failures INTEGER NOT NULL DEFAULT 0,
+    last_failure INTEGER NOT NULL,
+    locked_until INTEGER,
+    unique (kind, value)
+);
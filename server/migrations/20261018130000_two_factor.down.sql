@@ -0,0 +1,4 @@
+-- Add down migration script here
+drop table recovery_codes;
+ALTER TABLE users DROP COLUMN totp_last_step;
+ALTER TABLE users DROP COLUMN totp_secret;
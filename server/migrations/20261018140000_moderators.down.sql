@@ -0,0 +1,6 @@
+-- Add down migration script here
+delete from groups_permissions where permission_id in (select id from permissions where name in ('mod.read', 'mod.write'));
+delete from users_groups where group_id = (select id from groups where name = 'moderators');
+delete from groups_permissions where group_id = (select id from groups where name = 'moderators');
+delete from permissions where name in ('mod.read', 'mod.write');
+delete from groups where name = 'moderators';
@@ -0,0 +1,21 @@
+-- Add up migration script here
+insert into groups (name) values ('moderators');
+
+insert into permissions (name) values ('mod.read');
+insert into permissions (name) values ('mod.write');
+
+-- Moderators review adverts, admins can do that too.
+insert into groups_permissions (group_id, permission_id)
+values (
+    (select id from groups where name = 'moderators'),
+    (select id from permissions where name = 'mod.read')
+), (
+    (select id from groups where name = 'moderators'),
+    (select id from permissions where name = 'mod.write')
+), (
+    (select id from groups where name = 'admins'),
+    (select id from permissions where name = 'mod.read')
+), (
+    (select id from groups where name = 'admins'),
+    (select id from permissions where name = 'mod.write')
+);
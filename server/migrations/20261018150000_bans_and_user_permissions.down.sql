@@ -0,0 +1,5 @@
+-- Add down migration script here
+drop table users_permissions;
+ALTER TABLE users DROP COLUMN was_active;
+ALTER TABLE users DROP COLUMN ban_reason;
+ALTER TABLE users DROP COLUMN banned_until;
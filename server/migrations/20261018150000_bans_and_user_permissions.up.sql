@@ -0,0 +1,13 @@
+-- Add up migration script here
+ALTER TABLE users ADD COLUMN banned_until INTEGER;
+ALTER TABLE users ADD COLUMN ban_reason TEXT;
+-- Whether the user was active before the ban, restored when it is lifted
+ALTER TABLE users ADD COLUMN was_active BOOLEAN NOT NULL DEFAULT FALSE;
+
+-- Per-user permission overrides on top of group permissions, granted = FALSE denies.
+create table if not exists users_permissions (
+    user_id integer not null references users(id),
+    permission_id integer not null references permissions(id),
+    granted boolean not null,
+    primary key (user_id, permission_id)
+);
@@ -0,0 +1,10 @@
+-- Add up migration script here
+CREATE TABLE if not exists invites (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    code TEXT NOT NULL UNIQUE,
+    created_by INTEGER references users(id),
+    created_at INTEGER NOT NULL,
+    expires_at INTEGER,
+    used_by INTEGER references users(id),
+    used_at INTEGER
+);
@@ -0,0 +1,11 @@
+-- Add up migration script here
+CREATE TABLE if not exists reports (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    advert_id INTEGER NOT NULL references adverts(id) ON DELETE CASCADE,
+    reporter_id INTEGER NOT NULL references users(id) ON DELETE CASCADE,
+    reason TEXT NOT NULL,
+    details TEXT,
+    created_at INTEGER NOT NULL,
+    resolved BOOLEAN NOT NULL DEFAULT FALSE,
+    unique (advert_id, reporter_id)
+);
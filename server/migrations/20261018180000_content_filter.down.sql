@@ -0,0 +1,5 @@
+-- Add down migration script here
+DROP INDEX adverts_content_hash;
+ALTER TABLE adverts DROP COLUMN content_hash;
+ALTER TABLE adverts DROP COLUMN moderation_rule;
+drop table filter_rules;
@@ -0,0 +1,15 @@
+-- Add up migration script here
+CREATE TABLE if not exists filter_rules (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    kind TEXT NOT NULL,
+    pattern TEXT NOT NULL DEFAULT '',
+    action TEXT NOT NULL
+);
+
+-- Exact reposts wait for a moderator by default, other rules are added by admins
+INSERT INTO filter_rules(kind, action) VALUES('duplicate', 'hold');
+
+ALTER TABLE adverts ADD COLUMN moderation_rule TEXT;
+-- Digest of the normalized text, filled for new and edited adverts
+ALTER TABLE adverts ADD COLUMN content_hash TEXT;
+CREATE INDEX if not exists adverts_content_hash ON adverts(content_hash);
@@ -0,0 +1,3 @@
+-- Add down migration script here
+DROP INDEX adverts_without_fingerprint;
+ALTER TABLE adverts DROP COLUMN fingerprint;
@@ -0,0 +1,5 @@
+-- Add up migration script here
+-- Simhash of the normalized text, filled for new and edited adverts
+ALTER TABLE adverts ADD COLUMN fingerprint INTEGER;
+-- Adverts left to fingerprint by the backfill job, empty once it is done
+CREATE INDEX if not exists adverts_without_fingerprint ON adverts(id) WHERE fingerprint IS NULL;
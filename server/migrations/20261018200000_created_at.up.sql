@@ -0,0 +1,5 @@
+-- Add up migration script here
+-- Rows created before this migration get 0, so old accounts count as old enough
+-- and old adverts don't count towards hourly and daily quotas
+ALTER TABLE users ADD COLUMN created_at INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE adverts ADD COLUMN created_at INTEGER NOT NULL DEFAULT 0;
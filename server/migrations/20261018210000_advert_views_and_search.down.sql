@@ -0,0 +1,6 @@
+-- Add down migration script here
+DROP TRIGGER adverts_fts_update;
+DROP TRIGGER adverts_fts_delete;
+DROP TRIGGER adverts_fts_insert;
+DROP TABLE adverts_fts;
+ALTER TABLE adverts DROP COLUMN views;
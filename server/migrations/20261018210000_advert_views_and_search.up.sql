@@ -0,0 +1,17 @@
+-- Add up migration script here
+ALTER TABLE adverts ADD COLUMN views INTEGER NOT NULL DEFAULT 0;
+
+-- Full-text index over adverts for the similar adverts block, kept in sync by triggers
+CREATE VIRTUAL TABLE adverts_fts USING fts5(title, content, content='adverts', content_rowid='id');
+INSERT INTO adverts_fts(adverts_fts) VALUES ('rebuild');
+
+CREATE TRIGGER adverts_fts_insert AFTER INSERT ON adverts BEGIN
+    INSERT INTO adverts_fts(rowid, title, content) VALUES (new.id, new.title, new.content);
+END;
+CREATE TRIGGER adverts_fts_delete AFTER DELETE ON adverts BEGIN
+    INSERT INTO adverts_fts(adverts_fts, rowid, title, content) VALUES ('delete', old.id, old.title, old.content);
+END;
+CREATE TRIGGER adverts_fts_update AFTER UPDATE OF title, content ON adverts BEGIN
+    INSERT INTO adverts_fts(adverts_fts, rowid, title, content) VALUES ('delete', old.id, old.title, old.content);
+    INSERT INTO adverts_fts(rowid, title, content) VALUES (new.id, new.title, new.content);
+END;
@@ -0,0 +1,3 @@
+-- Add down migration script here
+DROP TABLE advert_daily_views;
+DROP TABLE advert_views;
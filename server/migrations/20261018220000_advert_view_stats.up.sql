@@ -0,0 +1,17 @@
+-- Add up migration script here
+-- Recent views, one per advert and session, pruned after they are aggregated
+CREATE TABLE advert_views (
+    advert_id INTEGER NOT NULL REFERENCES adverts(id) ON DELETE CASCADE,
+    viewer TEXT NOT NULL,
+    viewed_at INTEGER NOT NULL,
+    PRIMARY KEY (advert_id, viewer)
+);
+CREATE INDEX advert_views_viewed_at ON advert_views(viewed_at);
+
+-- Day is the number of days since unix epoch, UTC
+CREATE TABLE advert_daily_views (
+    advert_id INTEGER NOT NULL REFERENCES adverts(id) ON DELETE CASCADE,
+    day INTEGER NOT NULL,
+    views INTEGER NOT NULL,
+    PRIMARY KEY (advert_id, day)
+);
@@ -0,0 +1,2 @@
+-- Add down migration script here
+ALTER TABLE adverts DROP COLUMN moderated_at;
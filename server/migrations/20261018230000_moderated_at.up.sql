@@ -0,0 +1,3 @@
+-- Add up migration script here
+-- Time of the first moderator decision on the advert, NULL until reviewed
+ALTER TABLE adverts ADD COLUMN moderated_at INTEGER;
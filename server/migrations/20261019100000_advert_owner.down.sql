@@ -0,0 +1,10 @@
+-- Add down migration script here
+CREATE TABLE if not exists users_adverts (
+    user_id INTEGER NOT NULL,
+    advert_id INTEGER NOT NULL,
+    primary key (user_id, advert_id)
+);
+INSERT INTO users_adverts(user_id, advert_id) SELECT user_id, id FROM adverts WHERE user_id IS NOT NULL;
+
+DROP INDEX adverts_user_id;
+ALTER TABLE adverts DROP COLUMN user_id;
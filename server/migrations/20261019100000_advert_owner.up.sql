@@ -0,0 +1,13 @@
+-- Add up migration script here
+-- Every advert has exactly one author, so ownership moves from users_adverts to a column.
+-- Deleting the user deletes their adverts, and with them reports and view stats.
+ALTER TABLE adverts ADD COLUMN user_id INTEGER REFERENCES users(id) ON DELETE CASCADE;
+CREATE INDEX if not exists adverts_user_id ON adverts(user_id);
+
+-- Links to missing users are dropped, such adverts are kept without an owner
+UPDATE adverts SET user_id = (
+    SELECT MIN(ua.user_id) FROM users_adverts ua JOIN users u ON u.id = ua.user_id
+    WHERE ua.advert_id = adverts.id
+);
+
+DROP TABLE users_adverts;
@@ -0,0 +1,3 @@
+-- Add up migration script here
+-- Keyset pagination of the main page walks published adverts by id
+CREATE INDEX if not exists adverts_published ON adverts(published, id);
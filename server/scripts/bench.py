@@ -0,0 +1,99 @@
+#!/usr/bin/env python3
+"""Load test of a running server.
+
+Readers fetch the main page and an advert page in turn, writers log in and save their
+profile settings, which is a write transaction per request. Every client is a separate
+process with one keep-alive connection. Prints requests per second and p50/p99 latency.
+
+    python3 server/scripts/bench.py 10 4 2
+"""
+import argparse
+import http.client
+import multiprocessing as mp
+import re
+import time
+import urllib.parse
+
+FORM = "application/x-www-form-urlencoded"
+CSRF_TOKEN = re.compile(r'name="csrf_token"[^>]*value="([^"]*)"')
+
+
+def connect(args):
+    return http.client.HTTPConnection(args.host, args.port, timeout=30)
+
+
+def request(conn, jar, method, path, body=None):
+    headers = {"Cookie": "; ".join(f"{k}={v}" for k, v in jar.items())}
+    if body is not None:
+        headers["Content-Type"] = FORM
+        body = urllib.parse.urlencode(body)
+    conn.request(method, path, body, headers)
+    response = conn.getresponse()
+    page = response.read().decode()
+    for name, value in response.getheaders():
+        if name.lower() == "set-cookie":
+            key, _, rest = value.partition("=")
+            jar[key] = rest.split(";")[0]
+    return page
+
+
+def reader(args, deadline, results):
+    conn, jar, latencies = connect(args), {}, []
+    paths = ["/", f"/item/{args.item}"]
+    while time.time() < deadline:
+        started = time.time()
+        request(conn, jar, "GET", paths[len(latencies) % 2])
+        latencies.append(time.time() - started)
+    results.put(("read", latencies))
+
+
+def writer(args, deadline, results):
+    conn, jar, latencies = connect(args), {}, []
+    request(conn, jar, "POST", "/login", {"username": args.user, "password": args.password})
+    while time.time() < deadline:
+        page = request(conn, jar, "GET", "/profile/settings")
+        token = CSRF_TOKEN.search(page).group(1)
+        form = {"csrf_token": token, "display_name": f"u{len(latencies)}",
+                "email": "", "contact_phone": ""}
+        started = time.time()
+        request(conn, jar, "POST", "/profile/settings", form)
+        latencies.append(time.time() - started)
+    results.put(("write", latencies))
+
+
+def main():
+    parser = argparse.ArgumentParser(description="Load test of a running server")
+    parser.add_argument("seconds", type=int)
+    parser.add_argument("readers", type=int)
+    parser.add_argument("writers", type=int)
+    parser.add_argument("--host", default="localhost")
+    parser.add_argument("--port", type=int, default=3000)
+    parser.add_argument("--item", type=int, default=1, help="id of a published advert")
+    parser.add_argument("--user", default="user", help="active user for the writers")
+    parser.add_argument("--password", default="123")
+    args = parser.parse_args()
+
+    results = mp.Queue()
+    # Processes take a moment to start, the run is timed from the deadline
+    deadline = time.time() + args.seconds
+    clients = [mp.Process(target=reader, args=(args, deadline, results))
+               for _ in range(args.readers)]
+    clients += [mp.Process(target=writer, args=(args, deadline, results))
+                for _ in range(args.writers)]
+    for client in clients:
+        client.start()
+    finished = [results.get() for _ in clients]
+    for client in clients:
+        client.join()
+
+    for kind in ("read", "write"):
+        latencies = sorted(l for k, run in finished if k == kind for l in run)
+        if latencies:
+            p50 = latencies[len(latencies) // 2] * 1000
+            p99 = latencies[int(len(latencies) * 0.99)] * 1000
+            print(f"{kind}: {len(latencies) / args.seconds:.0f} req/s"
+                  f"  p50 {p50:.1f}ms  p99 {p99:.1f}ms")
+
+
+if __name__ == "__main__":
+    main()
@@ -1,23 +1,49 @@
-use std::{collections::HashSet, fmt::Display, sync::Arc};
+use std::{collections::HashSet, fmt::Display};
 
 use async_trait::async_trait;
 use axum_login::{AuthnBackend, AuthzBackend, UserId};
 use password_auth::verify_password;
 use serde::Deserialize;
 use sqlx::{FromRow, Pool, Sqlite};
-use tokio::{sync::RwLock, task};
+use tokio::task;
+use tracing::error;
 
-use crate::auth_models::User;
+use crate::{auth_models::User, db};
 
 #[derive(Clone)]
 pub struct AuthBackend {
-    pub db: Arc<RwLock<Pool<Sqlite>>>,
+    pub db: Pool<Sqlite>,
 }
 
 impl AuthBackend {
-    pub fn new(db: Arc<RwLock<Pool<Sqlite>>>) -> Self {
+    pub fn new(db: Pool<Sqlite>) -> Self {
         Self { db }
     }
+
+    /// Permissions granted (`granted = true`) or denied to this user personally
+    async fn get_permission_overrides(
+        &self,
+        user: &User,
+        granted: bool,
+    ) -> Result<HashSet<AuthPermission>, AuthError> {
+        let db = &self.db;
+        let permissions: Vec<AuthPermission> = sqlx::query_as(
+            r#"
+            select permissions.name
+            from users
+            join users_permissions on users.id = users_permissions.user_id
+            join permissions on users_permissions.permission_id = permissions.id
+            where users.id = ? AND users.active = TRUE AND users_permissions.granted = ?
+            "#,
+        )
+        .bind(user.id)
+        .bind(granted)
+        .fetch_all(db)
+        .await
+        .map_err(AuthError::SQLError)?;
+
+        Ok(permissions.into_iter().collect())
+    }
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -44,11 +70,21 @@ impl From<&str> for AuthPermission {
 pub enum AuthError {
     SQLError(sqlx::Error),
     WrongCreds,
+    PendingActivation,
+    Banned {
+        until: Option<i64>,
+        reason: Option<String>,
+    },
 }
 
 impl Display for AuthError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str("auth error")
+        match self {
+            AuthError::SQLError(e) => write!(f, "auth error: {}", e),
+            AuthError::WrongCreds => f.write_str("auth error: wrong credentials"),
+            AuthError::PendingActivation => f.write_str("auth error: user is not activated"),
+            AuthError::Banned { .. } => f.write_str("auth error: user is banned"),
+        }
     }
 }
 
@@ -62,38 +98,65 @@ impl AuthnBackend for AuthBackend {
         &self,
         creds: Self::Credentials,
     ) -> Result<Option<Self::User>, Self::Error> {
-        let db = self.db.read().await;
+        let db = &self.db;
         let user: Option<Self::User> = sqlx::query_as("select * from users where username = ? ")
             .bind(creds.username)
-            .fetch_optional(&*db)
+            .fetch_optional(db)
             .await
             .map_err(|e| {
-                println!("SQL Error {}", e);
+                error!(error = %e, "Failed to get user");
                 AuthError::SQLError(e)
             })?;
 
-        task::spawn_blocking(move || {
+        let user = task::spawn_blocking(move || {
             // We're using password-based authentication--this works by comparing our form
             // input with an argon2 password hash.
-            Ok(user.filter(|user| {
-                let is_ok = verify_password(&creds.password, &user.password_hash).is_ok();
-                is_ok
-            }))
+            user.filter(|user| verify_password(&creds.password, &user.password_hash).is_ok())
         })
         .await
-        .map_err(|_| {
-            println!("Wrong creds");
+        .map_err(|e| {
+            error!(error = %e, "Password check task failed");
             AuthError::WrongCreds
-        })?
+        })?;
+
+        // Account state is revealed only to someone who knows the password
+        match user {
+            Some(user) if user.banned => {
+                // Expired bans are lifted right away, the unban task may not have run yet
+                let expired = user.banned_until.is_some_and(|until| until <= db::now());
+                let unbanned = if expired {
+                    db::unban_user(db, user.id).await.ok()
+                } else {
+                    None
+                };
+                match unbanned {
+                    Some(true) => Ok(Some(User {
+                        active: true,
+                        banned: false,
+                        banned_until: None,
+                        ban_reason: None,
+                        ..user
+                    })),
+                    // Banned before the account was approved, it still waits for approval
+                    Some(false) => Err(AuthError::PendingActivation),
+                    None => Err(AuthError::Banned {
+                        until: user.banned_until,
+                        reason: user.ban_reason,
+                    }),
+                }
+            }
+            Some(user) if !user.active => Err(AuthError::PendingActivation),
+            user => Ok(user),
+        }
     }
 
     async fn get_user(&self, user_id: &UserId<Self>) -> Result<Option<Self::User>, Self::Error> {
-        let db = self.db.read().await;
+        let db = &self.db;
         let user = sqlx::query_as("select * from users where id = ? AND active = TRUE")
             .bind(user_id)
-            .fetch_optional(&*db)
+            .fetch_optional(db)
             .await
-            .map_err(|e| AuthError::SQLError(e))?;
+            .map_err(AuthError::SQLError)?;
         Ok(user)
     }
 }
@@ -102,11 +165,12 @@ impl AuthnBackend for AuthBackend {
 impl AuthzBackend for AuthBackend {
     type Permission = AuthPermission;
 
+    // Permissions are read on every check, so changes from the admin pages apply immediately
     async fn get_group_permissions(
         &self,
         user: &Self::User,
     ) -> Result<HashSet<Self::Permission>, Self::Error> {
-        let db = self.db.read().await;
+        let db = &self.db;
         let permissions: Vec<Self::Permission> = sqlx::query_as(
             r#"
             select distinct permissions.name
@@ -118,13 +182,33 @@ impl AuthzBackend for AuthBackend {
             "#,
         )
         .bind(user.id)
-        .fetch_all(&*db)
+        .fetch_all(db)
         .await
         .map_err(|e| {
-            dbg!(&e);
+            error!(error = %e, "Failed to get group permissions");
             AuthError::SQLError(e)
         })?;
 
         Ok(permissions.into_iter().collect())
     }
+
+    async fn get_user_permissions(
+        &self,
+        user: &Self::User,
+    ) -> Result<HashSet<Self::Permission>, Self::Error> {
+        self.get_permission_overrides(user, true).await
+    }
+
+    /// Group permissions plus per-user grants, minus per-user denies
+    async fn get_all_permissions(
+        &self,
+        user: &Self::User,
+    ) -> Result<HashSet<Self::Permission>, Self::Error> {
+        let mut permissions = self.get_group_permissions(user).await?;
+        permissions.extend(self.get_user_permissions(user).await?);
+        for denied in self.get_permission_overrides(user, false).await? {
+            permissions.remove(&denied);
+        }
+        Ok(permissions)
+    }
 }
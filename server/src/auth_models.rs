@@ -7,6 +7,12 @@ pub struct User {
     pub username: String,
     pub password_hash: String,
     pub active: bool,
+    pub banned: bool,
+    pub banned_until: Option<i64>,
+    pub ban_reason: Option<String>,
+    pub totp_secret: Option<String>,
+    pub totp_last_step: Option<i64>,
+    pub created_at: i64,
 }
 
 impl AuthUser for User {
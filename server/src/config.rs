@@ -0,0 +1,142 @@
+use std::net::{IpAddr, SocketAddr};
+
+use axum::http::HeaderMap;
+use clap::{Parser, ValueEnum};
+
+use crate::models::AdvertQuota;
+
+#[derive(Parser, Clone, Debug)]
+#[command(version, about, long_about = None)]
+pub struct Config {
+    /// Force users with admin.* permissions to enrol two-factor authentication
+    #[arg(long, env = "REQUIRE_ADMIN_2FA")]
+    pub require_admin_2fa: bool,
+
+    /// Who can register and whether new accounts wait for a moderator
+    #[arg(long, env = "REGISTRATION_MODE", value_enum, default_value_t = RegistrationMode::Moderated)]
+    pub registration_mode: RegistrationMode,
+
+    /// Unpublish adverts after this many distinct users reported them, 0 disables
+    #[arg(long, env = "REPORT_THRESHOLD", default_value_t = 3)]
+    pub report_threshold: i64,
+
+    /// Publish adverts passing the content filter right away instead of waiting for a moderator
+    #[arg(long, env = "AUTO_APPROVE")]
+    pub auto_approve: bool,
+
+    /// Refuse adverts nearly duplicating another published advert of the same user
+    #[arg(long, env = "BLOCK_OWN_DUPLICATES")]
+    pub block_own_duplicates: bool,
+
+    /// Most published and pending adverts a user can have at once, 0 disables
+    #[arg(long, env = "MAX_PUBLISHED_ADVERTS", default_value_t = 0)]
+    pub max_published_adverts: i64,
+
+    /// Most new adverts per user in an hour, 0 disables
+    #[arg(long, env = "MAX_ADVERTS_PER_HOUR", default_value_t = 0)]
+    pub max_adverts_per_hour: i64,
+
+    /// Most new adverts per user in a day, 0 disables
+    #[arg(long, env = "MAX_ADVERTS_PER_DAY", default_value_t = 0)]
+    pub max_adverts_per_day: i64,
+
+    /// Hours since registration before a user can post adverts
+    #[arg(long, env = "MIN_ACCOUNT_AGE_HOURS", default_value_t = 0)]
+    pub min_account_age_hours: i64,
+
+    /// Bearer token for scraping `/metrics`, the endpoint is disabled without it
+    #[arg(long, env = "METRICS_TOKEN")]
+    pub metrics_token: Option<String>,
+
+    /// Header with the client address set by a reverse proxy, like `X-Real-IP` or
+    /// `X-Forwarded-For` (its last address is used). Set it only if the server can't be
+    /// reached bypassing the proxy, clients could fake their address otherwise.
+    #[arg(long, env = "TRUSTED_PROXY_HEADER")]
+    pub trusted_proxy_header: Option<String>,
+
+    /// Log output format, the level is set with `RUST_LOG`
+    #[arg(long, env = "LOG_FORMAT", value_enum, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+}
+
+impl Config {
+    /// Address of the client, from the trusted proxy header if it is set and valid
+    pub fn client_ip(&self, addr: &SocketAddr, headers: &HeaderMap) -> IpAddr {
+        self.trusted_proxy_header
+            .as_deref()
+            .and_then(|header| headers.get(header))
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.rsplit(',').next())
+            .and_then(|ip| ip.trim().parse().ok())
+            .unwrap_or(addr.ip())
+    }
+
+    pub fn advert_quota(&self) -> AdvertQuota {
+        AdvertQuota {
+            max_published: self.max_published_adverts,
+            auto_approve: self.auto_approve,
+            max_per_hour: self.max_adverts_per_hour,
+            max_per_day: self.max_adverts_per_day,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RegistrationMode {
+    /// Anyone can register, accounts are active right away
+    Open,
+    /// Anyone can register, accounts wait for activation by a moderator
+    Moderated,
+    /// Registration needs an invite code from an admin, accounts are active right away
+    Invite,
+    /// Nobody can register
+    Closed,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human readable lines
+    Text,
+    /// One json object per line
+    Json,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use axum::http::HeaderMap;
+    use clap::Parser;
+
+    use super::Config;
+
+    fn headers(forwarded_for: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", forwarded_for.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn client_ip_is_the_peer_without_trusted_header() {
+        let config = Config::parse_from(["simple_bulletin"]);
+        let addr: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        assert_eq!(config.client_ip(&addr, &headers("1.2.3.4")), addr.ip());
+    }
+
+    #[test]
+    fn client_ip_is_taken_from_trusted_header() {
+        let config = Config::parse_from([
+            "simple_bulletin",
+            "--trusted-proxy-header",
+            "X-Forwarded-For",
+        ]);
+        let addr: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        // The proxy appends the address it sees, earlier ones are up to the client
+        assert_eq!(
+            config.client_ip(&addr, &headers("6.6.6.6, 1.2.3.4")),
+            "1.2.3.4".parse::<std::net::IpAddr>().unwrap()
+        );
+        assert_eq!(config.client_ip(&addr, &headers("garbage")), addr.ip());
+        assert_eq!(config.client_ip(&addr, &HeaderMap::new()), addr.ip());
+    }
+}
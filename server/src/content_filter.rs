@@ -0,0 +1,348 @@
+//! Rule based pre-moderation of advert text. Rules are managed by admins on /admin/filters,
+//! a rejecting rule wins over a holding one, adverts matching no rule are approved.
+
+use std::{
+    collections::HashMap,
+    sync::{Mutex, OnceLock, PoisonError},
+};
+
+use data_encoding::HEXLOWER;
+use regex::{Regex, RegexBuilder};
+use ring::digest;
+use sqlx::{Pool, Sqlite};
+use tracing::warn;
+
+use crate::{
+    db,
+    models::{AdvertFingerprint, AdvertModeration, FilterRule, FilterRuleAction, FilterRuleKind},
+};
+
+/// Compiled size limit for admin supplied regexes
+const REGEX_SIZE_LIMIT: usize = 1 << 20;
+/// Fingerprints differing in at most this many bits belong to near-duplicate texts.
+/// Copies with a few phrases reworded land under it, different listings around 25 and more.
+const NEAR_DUPLICATE_MAX_DISTANCE: u32 = 10;
+/// Moderators see near-duplicates among this many newest adverts
+pub const DUPLICATE_CANDIDATES_LIMIT: i64 = 1000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Decision {
+    Approve,
+    /// Stored unpublished until a moderator looks at it, with the matched rule
+    Hold(String),
+    /// Not stored at all, with the matched rule
+    Reject(String),
+}
+
+fn link_regex() -> &'static Regex {
+    static LINK: OnceLock<Regex> = OnceLock::new();
+    LINK.get_or_init(|| {
+        Regex::new(
+            r"(?i)\b(https?://|www\.)\S+|\b[a-z0-9-]+\.(com|net|org|ru|io|info|biz|me|xyz)\b",
+        )
+        .expect("Link regex is valid")
+    })
+}
+
+fn phone_regex() -> &'static Regex {
+    static PHONE: OnceLock<Regex> = OnceLock::new();
+    // Only phone-like groupings, so dates, prices and ranges of them don't match
+    PHONE.get_or_init(|| {
+        Regex::new(
+            r"(?x)
+            (\+\d{1,3}|\b8)[\s-]?\(?\d{3}\)?[\s-]?\d{3}[\s-]?\d{2}[\s-]?\d{2}\b  # +7 (999) 123-45-67
+            | \b\d{10,12}\b                                                  # 89991234567
+            | \b\d{3}[\s-]\d{2}[\s-]\d{2}\b                                   # 123-45-67
+            ",
+        )
+        .expect("Phone regex is valid")
+    })
+}
+
+/// Compiled admin regexes by rule id along with the pattern, `None` if it doesn't compile
+type RuleRegexes = HashMap<i64, (String, Option<Regex>)>;
+
+fn rule_regexes() -> &'static Mutex<RuleRegexes> {
+    static RULE_REGEXES: OnceLock<Mutex<RuleRegexes>> = OnceLock::new();
+    RULE_REGEXES.get_or_init(Default::default)
+}
+
+/// Compiles the regex of a rule once, again only after its pattern is edited
+fn rule_regex(rule: &FilterRule) -> Option<Regex> {
+    let mut regexes = rule_regexes()
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    if let Some((pattern, regex)) = regexes.get(&rule.id) {
+        if *pattern == rule.pattern {
+            return regex.clone();
+        }
+    }
+    let regex = build_regex(&rule.pattern)
+        .map_err(|e| warn!(rule_id = rule.id, error = %e, "Filter rule has invalid regex"))
+        .ok();
+    regexes.insert(rule.id, (rule.pattern.clone(), regex.clone()));
+    regex
+}
+
+fn build_regex(pattern: &str) -> Result<Regex, regex::Error> {
+    RegexBuilder::new(pattern)
+        .case_insensitive(true)
+        .size_limit(REGEX_SIZE_LIMIT)
+        .build()
+}
+
+/// Lowercase words separated by single spaces, punctuation dropped
+pub fn normalize(text: &str) -> String {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Adverts differing only in case, punctuation or spacing get the same hash
+pub fn content_hash(title: &str, content: &str) -> String {
+    let normalized = format!("{}\n{}", normalize(title), normalize(content));
+    HEXLOWER.encode(digest::digest(&digest::SHA256, normalized.as_bytes()).as_ref())
+}
+
+/// Stable 64-bit hash, FNV-1a with a splitmix64 finalizer to spread the bits evenly.
+/// Fingerprints are stored, so this must never change between releases.
+fn word_hash(word: &str) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in word.bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^ (hash >> 31)
+}
+
+/// Simhash of the normalized words, similar texts get fingerprints differing in few bits
+pub fn fingerprint(title: &str, content: &str) -> i64 {
+    let mut weights = [0i64; 64];
+    for word in normalize(&format!("{} {}", title, content)).split(' ') {
+        let hash = word_hash(word);
+        for (bit, weight) in weights.iter_mut().enumerate() {
+            if hash >> bit & 1 == 1 {
+                *weight += 1;
+            } else {
+                *weight -= 1;
+            }
+        }
+    }
+    let fingerprint = weights
+        .iter()
+        .enumerate()
+        .filter(|(_, weight)| **weight > 0)
+        .fold(0u64, |fingerprint, (bit, _)| fingerprint | 1 << bit);
+    fingerprint as i64
+}
+
+pub fn is_near_duplicate(a: i64, b: i64) -> bool {
+    (a ^ b).count_ones() <= NEAR_DUPLICATE_MAX_DISTANCE
+}
+
+/// Ids of adverts similar to `fingerprint`, except `exclude` itself
+pub fn near_duplicates(
+    fingerprint: i64,
+    exclude: i64,
+    candidates: &[AdvertFingerprint],
+) -> Vec<i64> {
+    candidates
+        .iter()
+        .filter(|candidate| {
+            candidate.id != exclude && is_near_duplicate(candidate.fingerprint, fingerprint)
+        })
+        .map(|candidate| candidate.id)
+        .collect()
+}
+
+/// Checks that an admin supplied rule can be applied
+pub fn validate_rule(kind: FilterRuleKind, pattern: &str) -> Result<(), &'static str> {
+    match kind {
+        FilterRuleKind::Word if normalize(pattern).is_empty() => Err("Word must not be empty"),
+        FilterRuleKind::Regex if pattern.is_empty() => Err("Regex must not be empty"),
+        FilterRuleKind::Regex if build_regex(pattern).is_err() => Err("Regex is not valid"),
+        _ => Ok(()),
+    }
+}
+
+fn matches(rule: &FilterRule, text: &str, duplicate: bool) -> bool {
+    match rule.kind {
+        FilterRuleKind::Word => {
+            format!(" {} ", normalize(text)).contains(&format!(" {} ", normalize(&rule.pattern)))
+        }
+        FilterRuleKind::Regex => rule_regex(rule).is_some_and(|regex| regex.is_match(text)),
+        FilterRuleKind::Link => link_regex().is_match(text),
+        FilterRuleKind::Phone => phone_regex().is_match(text),
+        FilterRuleKind::Duplicate => duplicate,
+    }
+}
+
+pub fn check(rules: &[FilterRule], title: &str, content: &str, duplicate: bool) -> Decision {
+    let text = format!("{}\n{}", title, content);
+    let mut held = None;
+    for rule in rules.iter().filter(|rule| matches(rule, &text, duplicate)) {
+        match rule.action {
+            FilterRuleAction::Reject => return Decision::Reject(rule.to_string()),
+            FilterRuleAction::Hold => {
+                held.get_or_insert_with(|| rule.to_string());
+            }
+        }
+    }
+    held.map(Decision::Hold).unwrap_or(Decision::Approve)
+}
+
+pub struct Checked {
+    pub decision: Decision,
+    pub content_hash: String,
+    pub fingerprint: i64,
+}
+
+impl Checked {
+    /// What to store with the advert, or the rejecting rule. Approved adverts are published
+    /// only if `publish_approved` is set.
+    pub fn into_moderation(self, publish_approved: bool) -> Result<AdvertModeration, String> {
+        let (published, moderation_rule) = match self.decision {
+            Decision::Approve => (publish_approved, None),
+            Decision::Hold(rule) => (false, Some(rule)),
+            Decision::Reject(rule) => return Err(rule),
+        };
+        Ok(AdvertModeration {
+            published,
+            moderation_rule,
+            content_hash: self.content_hash,
+            fingerprint: self.fingerprint,
+        })
+    }
+}
+
+/// Runs all rules against a new advert or an edit of `advert_id`
+pub async fn moderate(
+    db: &Pool<Sqlite>,
+    advert_id: Option<i64>,
+    title: &str,
+    content: &str,
+) -> Result<Checked, ()> {
+    let rules = db::get_filter_rules(db).await?;
+    let content_hash = content_hash(title, content);
+    let duplicate = if rules
+        .iter()
+        .any(|rule| rule.kind == FilterRuleKind::Duplicate)
+    {
+        db::has_duplicate_advert(db, &content_hash, advert_id).await?
+    } else {
+        false
+    };
+    Ok(Checked {
+        decision: check(&rules, title, content, duplicate),
+        content_hash,
+        fingerprint: fingerprint(title, content),
+    })
+}
+
+/// Finds a published advert of `user_id` similar to `fingerprint`, other than `advert_id`
+pub async fn own_near_duplicate(
+    db: &Pool<Sqlite>,
+    user_id: i64,
+    advert_id: Option<i64>,
+    fingerprint: i64,
+) -> Result<Option<i64>, ()> {
+    let candidates = db::get_user_advert_fingerprints(db, user_id).await?;
+    Ok(
+        near_duplicates(fingerprint, advert_id.unwrap_or(-1), &candidates)
+            .first()
+            .copied(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fingerprint, phone_regex, NEAR_DUPLICATE_MAX_DISTANCE};
+
+    const LISTING: (&str, &str) = (
+        "Selling a mountain bike",
+        "Selling my mountain bike, aluminium frame, 21 gears, disc brakes, new tyres. \
+         Used for two summers, always kept in the garage. Pickup in the city centre, \
+         price is negotiable for a quick sale.",
+    );
+
+    fn distance(a: (&str, &str), b: (&str, &str)) -> u32 {
+        (fingerprint(a.0, a.1) ^ fingerprint(b.0, b.1)).count_ones()
+    }
+
+    #[test]
+    fn reworded_copies_are_near_duplicates() {
+        let reworded = (
+            "Selling mountain bike",
+            "Selling my mountain bike, aluminium frame, 21 speeds, hydraulic disc brakes, \
+             brand new tyres. Ridden for two summers, always kept in the garage. Pickup in \
+             the city centre, price is negotiable for a fast sale.",
+        );
+        assert!(distance(LISTING, reworded) <= NEAR_DUPLICATE_MAX_DISTANCE);
+    }
+
+    #[test]
+    fn case_and_punctuation_are_ignored() {
+        let shouted = (
+            "SELLING A MOUNTAIN BIKE!!!",
+            "selling my mountain bike - aluminium frame - 21 gears - disc brakes - new tyres \
+             used for two summers always kept in the garage pickup in the city centre \
+             price is negotiable for a quick sale",
+        );
+        assert_eq!(distance(LISTING, shouted), 0);
+    }
+
+    #[test]
+    fn different_listings_are_not_near_duplicates() {
+        for other in [
+            (
+                "Two room flat for rent",
+                "Two room flat on the fifth floor, furnished, washing machine and fridge. \
+                 Ten minutes to the metro, long term only, no pets.",
+            ),
+            (
+                "Selling a road bike",
+                "Road bike, carbon frame, 22 gears, rim brakes. Ridden for one season, \
+                 small scratch on the fork. Delivery possible.",
+            ),
+            (
+                "Kitten looking for a home",
+                "Friendly grey kitten, three months old, litter trained, eats everything.",
+            ),
+        ] {
+            assert!(distance(LISTING, other) >= 2 * NEAR_DUPLICATE_MAX_DISTANCE);
+        }
+    }
+
+    #[test]
+    fn finds_phones() {
+        for text in [
+            "Call +7 (999) 123-45-67",
+            "8 999 123 45 67 after six",
+            "+79991234567",
+            "89991234567",
+            "+1 555 123 4567",
+            "tel. 123-45-67",
+        ] {
+            assert!(phone_regex().is_match(text), "{}", text);
+        }
+    }
+
+    #[test]
+    fn ignores_dates_and_prices() {
+        for text in [
+            "Available from 2024-01-15",
+            "Bought 15.01.2024",
+            "Price 10.000-20.000",
+            "Price 10 000 - 20 000 rub",
+            "1 500 000 rub",
+            "Rooms 1-2, floors 3-12",
+            "Model 1000-2000",
+        ] {
+            assert!(!phone_regex().is_match(text), "{}", text);
+        }
+    }
+}
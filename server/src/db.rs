@@ -1,259 +1,2012 @@
-use crate::{auth_models::User, models::Advert};
+use crate::{
+    auth_models::User,
+    models::{
+        Advert, AdvertFingerprint, AdvertModeration, AdvertQuota, AdvertStats, FilterRule,
+        FilterRuleAction, FilterRuleKind, Group, GroupMember, GroupPermission, Invite, LoginLock,
+        ModerationLatency, ReportReason, ReportedAdvert, Seller, UserPermission, UserSettings,
+        UserStats,
+    },
+    pagination::{Page, PageRequest},
+};
+use log::LevelFilter;
 use password_auth::generate_hash;
 use sqlx::{
     migrate::{MigrateDatabase, Migrator},
-    sqlite::SqliteConnectOptions,
-    Pool, Sqlite, SqlitePool,
+    pool::PoolConnection,
+    sqlite::{SqliteConnectOptions, SqliteJournalMode, SqliteSynchronous},
+    ConnectOptions, Pool, Sqlite, SqliteConnection, SqlitePool,
+};
+use std::{
+    ops::{Deref, DerefMut},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
+use tracing::{error, instrument};
 
 static MIGRATOR: Migrator = sqlx::migrate!();
 
+/// How long a connection waits for another writer before failing with SQLITE_BUSY
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Statements running longer than this are logged as warnings with their SQL
+const SLOW_STATEMENT: Duration = Duration::from_millis(100);
+
+/// Failed logins older than this are forgotten on the next failure
+const LOGIN_ATTEMPTS_WINDOW_SECS: i64 = 24 * 60 * 60;
+
+pub const SECS_PER_DAY: i64 = 24 * 60 * 60;
+/// Title words too common to make adverts similar, shorter than 3 letters ones are dropped too
+const SIMILAR_STOPWORDS: &[&str] = &[
+    "and",
+    "the",
+    "for",
+    "with",
+    "from",
+    "sale",
+    "sell",
+    "selling",
+    "new",
+    "used",
+    "buy",
+    "для",
+    "или",
+    "продам",
+    "продаю",
+    "куплю",
+    "новый",
+    "новая",
+    "новое",
+];
+
+pub fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+#[instrument(skip_all)]
 pub async fn create_db(db_url: &str) -> Result<Pool<Sqlite>, ()> {
     if !sqlx::Sqlite::database_exists(db_url).await.map_err(|e| {
-        println!("Failed to check if database exists {}", e);
-        ()
+        error!(error = %e, "Failed to check if database exists");
     })? {
         sqlx::Sqlite::create_database(db_url).await.map_err(|e| {
-            println!("Failed to create database {}", e);
-            ()
+            error!(error = %e, "Failed to create database");
         })?;
     }
 
     // Connect to the database
-    let connect_options = SqliteConnectOptions::new().filename(&db_url);
+    // WAL lets readers go on while a writer commits, concurrent writers wait for the lock
+    // instead of failing right away. Statements are logged inside the span of the db
+    // function and the request calling it.
+    let connect_options = SqliteConnectOptions::new()
+        .filename(db_url)
+        .journal_mode(SqliteJournalMode::Wal)
+        .synchronous(SqliteSynchronous::Normal)
+        .busy_timeout(BUSY_TIMEOUT)
+        .foreign_keys(true)
+        .log_statements(LevelFilter::Debug)
+        .log_slow_statements(LevelFilter::Warn, SLOW_STATEMENT);
     let db = SqlitePool::connect_with(connect_options)
         .await
         .map_err(|_| ())?;
 
     // Migrate the database
     MIGRATOR.run(&db).await.map_err(|e| {
-        println!("Migration error {}", e);
-        ()
+        error!(error = %e, "Migration error");
     })?;
     Ok(db)
 }
 
-pub async fn create_new_user(db: &Pool<Sqlite>, username: &str, password: &str) -> Result<(), ()> {
-    sqlx::query("INSERT INTO users(username, password_hash) VALUES(?, ?)")
-        .bind(username)
-        .bind(generate_hash(password))
-        .execute(db)
-        .await
-        .map_err(|_| ())?;
-    sqlx::query(
-        r#"INSERT INTO
-                 users_groups(user_id, group_id)
-                 VALUES(
-                    (SELECT id FROM users WHERE username = ?),
-                    (SELECT id FROM groups WHERE name = ?)
-                )"#,
-    )
-    .bind(username)
-    .bind("users")
-    .execute(db)
-    .await
-    .map_err(|_| ())?;
+#[instrument(skip_all)]
+pub async fn ping(db: &Pool<Sqlite>) -> Result<(), ()> {
+    sqlx::query("SELECT 1").execute(db).await.map_err(|e| {
+        error!(error = %e, "Database is unreachable");
+    })?;
     Ok(())
 }
 
-pub async fn create_new_advert(
+/// Whether every migration built into the binary has been applied successfully
+#[instrument(skip_all)]
+pub async fn migrations_current(db: &Pool<Sqlite>) -> Result<bool, ()> {
+    let applied: Vec<i64> =
+        sqlx::query_scalar("SELECT version FROM _sqlx_migrations WHERE success = true")
+            .fetch_all(db)
+            .await
+            .map_err(|e| {
+                error!(error = %e, "Failed to get applied migrations");
+            })?;
+    Ok(MIGRATOR
+        .iter()
+        .filter(|migration| migration.migration_type.is_up_migration())
+        .all(|migration| applied.contains(&migration.version)))
+}
+
+#[derive(Debug)]
+pub enum CreateUserError {
+    UsernameTaken,
+    InvalidInvite,
+    Internal,
+}
+
+/// Creates a user in `groups`. With `invite` the code is redeemed in the same
+/// transaction, so an invalid or already used code leaves no account behind.
+#[instrument(skip_all)]
+pub async fn create_new_user(
     db: &Pool<Sqlite>,
-    user_id: i64,
-    title: &str,
-    content: &str,
-) -> Result<i64, ()> {
-    let advert_id = sqlx::query("INSERT INTO adverts(title, content) VALUES(?, ?)")
-        .bind(title)
-        .bind(content)
-        .execute(db)
+    username: &str,
+    password: &str,
+    groups: &[&str],
+    active: bool,
+    invite: Option<&str>,
+) -> Result<(), CreateUserError> {
+    let mut tx = db.begin().await.map_err(|e| {
+        error!(error = %e, "Failed to start transaction");
+        CreateUserError::Internal
+    })?;
+    let user_id = sqlx::query(
+        "INSERT INTO users(username, password_hash, active, created_at) VALUES(?, ?, ?, ?)",
+    )
+    .bind(username)
+    .bind(generate_hash(password))
+    .bind(active)
+    .bind(now())
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| match e.as_database_error() {
+        Some(e) if e.is_unique_violation() => CreateUserError::UsernameTaken,
+        _ => {
+            error!(error = %e, "Failed to create user");
+            CreateUserError::Internal
+        }
+    })?
+    .last_insert_rowid();
+    for group in groups {
+        let added = sqlx::query(
+            r#"INSERT INTO
+                     users_groups(user_id, group_id)
+                     SELECT ?, id FROM groups WHERE name = ?"#,
+        )
+        .bind(user_id)
+        .bind(group)
+        .execute(&mut *tx)
         .await
         .map_err(|e| {
-            println!("Failed to create advert {}", e);
-            ()
+            error!(error = %e, "Failed to add user to group");
+            CreateUserError::Internal
         })?;
-    let new_advert_id = advert_id.last_insert_rowid();
-    sqlx::query("INSERT INTO users_adverts(user_id, advert_id) VALUES(?, ?)")
+        if added.rows_affected() == 0 {
+            error!(group, "Group of the new user doesn't exist");
+            return Err(CreateUserError::Internal);
+        }
+    }
+
+    if let Some(invite) = invite {
+        let redeemed = sqlx::query(
+            r#"UPDATE invites SET used_by = ?, used_at = ?
+               WHERE code = ? AND used_by IS NULL AND (expires_at IS NULL OR expires_at > ?)"#,
+        )
         .bind(user_id)
-        .bind(new_advert_id)
-        .execute(db)
+        .bind(now())
+        .bind(invite)
+        .bind(now())
+        .execute(&mut *tx)
         .await
         .map_err(|e| {
-            println!("Failed to join advert to user {}", e);
-            ()
+            error!(error = %e, "Failed to redeem invite");
+            CreateUserError::Internal
+        })?;
+        if redeemed.rows_affected() == 0 {
+            return Err(CreateUserError::InvalidInvite);
+        }
+    }
+
+    tx.commit().await.map_err(|e| {
+        error!(error = %e, "Failed to commit new user");
+        CreateUserError::Internal
+    })
+}
+
+/// Transaction holding the write lock from its start. A plain `BEGIN` only takes it on the
+/// first write, so of two transactions which read and then write one fails with SQLITE_BUSY
+/// instead of waiting, while `BEGIN IMMEDIATE` makes the second one wait for the first.
+struct ImmediateTransaction {
+    conn: Option<PoolConnection<Sqlite>>,
+}
+
+impl ImmediateTransaction {
+    async fn begin(db: &Pool<Sqlite>) -> Result<Self, sqlx::Error> {
+        let mut conn = db.acquire().await?;
+        sqlx::query("BEGIN IMMEDIATE").execute(&mut *conn).await?;
+        Ok(ImmediateTransaction { conn: Some(conn) })
+    }
+
+    async fn commit(self) -> Result<(), sqlx::Error> {
+        self.end("COMMIT").await
+    }
+
+    async fn rollback(self) -> Result<(), sqlx::Error> {
+        self.end("ROLLBACK").await
+    }
+
+    async fn end(mut self, statement: &str) -> Result<(), sqlx::Error> {
+        sqlx::query(statement).execute(&mut *self).await?;
+        // Only a finished transaction gives the connection back to the pool
+        drop(self.conn.take());
+        Ok(())
+    }
+}
+
+impl Deref for ImmediateTransaction {
+    type Target = SqliteConnection;
+
+    fn deref(&self) -> &SqliteConnection {
+        self.conn.as_deref().expect("transaction is open")
+    }
+}
+
+impl DerefMut for ImmediateTransaction {
+    fn deref_mut(&mut self) -> &mut SqliteConnection {
+        self.conn.as_deref_mut().expect("transaction is open")
+    }
+}
+
+impl Drop for ImmediateTransaction {
+    /// sqlx doesn't know about the transaction, a connection left in it (the request was
+    /// cancelled or COMMIT failed) is closed, which rolls it back, instead of going back to
+    /// the pool
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            drop(conn.detach());
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum CreateAdvertError {
+    /// Too many published adverts, with the limit
+    PublishedQuota(i64),
+    HourlyQuota(i64),
+    DailyQuota(i64),
+    Internal,
+}
+
+/// Creates the advert if the user is within `quota`, counting and inserting in one transaction
+#[instrument(skip_all)]
+pub async fn create_new_advert(
+    db: &Pool<Sqlite>,
+    user_id: i64,
+    title: &str,
+    content: &str,
+    moderation: &AdvertModeration,
+    quota: &AdvertQuota,
+) -> Result<i64, CreateAdvertError> {
+    // The counts must not change before the insert, so the write lock is taken up front
+    let mut tx = ImmediateTransaction::begin(db).await.map_err(|e| {
+        error!(error = %e, "Failed to start transaction");
+        CreateAdvertError::Internal
+    })?;
+    let result = insert_advert(&mut tx, user_id, title, content, moderation, quota).await;
+    if result.is_err() {
+        tx.rollback().await.map_err(|e| {
+            error!(error = %e, "Failed to roll back new advert");
+            CreateAdvertError::Internal
         })?;
-    Ok(new_advert_id)
+        return result;
+    }
+    tx.commit().await.map_err(|e| {
+        error!(error = %e, "Failed to commit new advert");
+        CreateAdvertError::Internal
+    })?;
+    result
+}
+
+async fn insert_advert(
+    tx: &mut SqliteConnection,
+    user_id: i64,
+    title: &str,
+    content: &str,
+    moderation: &AdvertModeration,
+    quota: &AdvertQuota,
+) -> Result<i64, CreateAdvertError> {
+    let now = now();
+    // Without auto-approve unreviewed adverts are pending, with it only the held ones are,
+    // adverts unpublished by their author or a moderator don't count
+    let (published, last_hour, last_day): (i64, i64, i64) = sqlx::query_as(
+        r#"SELECT COALESCE(SUM(a.published = TRUE OR (a.moderated_at IS NULL
+                   AND (a.moderation_rule IS NOT NULL OR NOT ?))), 0),
+               COALESCE(SUM(a.created_at > ?), 0),
+               COALESCE(SUM(a.created_at > ?), 0)
+           FROM adverts a
+           WHERE a.user_id = ?"#,
+    )
+    .bind(quota.auto_approve)
+    .bind(now - 60 * 60)
+    .bind(now - 24 * 60 * 60)
+    .bind(user_id)
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(|e| {
+        error!(error = %e, "Failed to count user adverts");
+        CreateAdvertError::Internal
+    })?;
+    if quota.max_published > 0 && published >= quota.max_published {
+        return Err(CreateAdvertError::PublishedQuota(quota.max_published));
+    }
+    if quota.max_per_hour > 0 && last_hour >= quota.max_per_hour {
+        return Err(CreateAdvertError::HourlyQuota(quota.max_per_hour));
+    }
+    if quota.max_per_day > 0 && last_day >= quota.max_per_day {
+        return Err(CreateAdvertError::DailyQuota(quota.max_per_day));
+    }
+
+    let advert_id = sqlx::query(
+        r#"INSERT INTO adverts(user_id, title, content, published, moderation_rule, content_hash,
+               fingerprint, created_at)
+           VALUES(?, ?, ?, ?, ?, ?, ?, ?)"#,
+    )
+    .bind(user_id)
+    .bind(title)
+    .bind(content)
+    .bind(moderation.published)
+    .bind(&moderation.moderation_rule)
+    .bind(&moderation.content_hash)
+    .bind(moderation.fingerprint)
+    .bind(now)
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| {
+        error!(error = %e, "Failed to create advert");
+        CreateAdvertError::Internal
+    })?;
+    Ok(advert_id.last_insert_rowid())
+}
+
+#[instrument(skip_all)]
+pub async fn update_advert(
+    db: &Pool<Sqlite>,
+    advert_id: i64,
+    title: &str,
+    content: &str,
+    moderation: &AdvertModeration,
+) -> Result<(), ()> {
+    sqlx::query(
+        r#"UPDATE adverts SET title = ?, content = ?, published = ?, moderation_rule = ?,
+               content_hash = ?, fingerprint = ?
+           WHERE id = ?"#,
+    )
+    .bind(title)
+    .bind(content)
+    .bind(moderation.published)
+    .bind(&moderation.moderation_rule)
+    .bind(&moderation.content_hash)
+    .bind(moderation.fingerprint)
+    .bind(advert_id)
+    .execute(db)
+    .await
+    .map_err(|e| {
+        error!(error = %e, "Failed to update advert");
+    })?;
+    Ok(())
 }
 
+#[instrument(skip_all)]
 pub async fn get_advert_by_id(
     db: &Pool<Sqlite>,
     user_id: Option<i64>,
     id: i64,
     is_admin: bool,
 ) -> Result<(Advert, bool), ()> {
-    let mut is_own = false;
     let result: Option<Advert> = if is_admin {
-        is_own = true;
-        sqlx::query_as("SELECT * FROM adverts WHERE id = ?")
-            .bind(id)
-    } else if let Some(user_id) = user_id {
-        let advert_user_id: i64 = sqlx::query_scalar("SELECT user_id FROM users_adverts WHERE advert_id = ?").bind(id).fetch_one(db).await.map_err(|e| {
-            println!("Failed to get item user {}", e);
-            ()})?;
-
-            is_own = advert_user_id == user_id;
-
-        sqlx::query_as("SELECT a.id, a.title, a.content, a.published FROM adverts a JOIN users_adverts ua ON a.id = ua.advert_id WHERE a.id = ? AND (a.published = true OR ua.user_id = ?)")
+        sqlx::query_as("SELECT * FROM adverts WHERE id = ?").bind(id)
+    } else {
+        // Anonymous users bind NULL, which never matches the owner
+        sqlx::query_as("SELECT * FROM adverts WHERE id = ? AND (published = true OR user_id = ?)")
             .bind(id)
             .bind(user_id)
-    } else {
-        sqlx::query_as("SELECT * FROM adverts WHERE id = ? AND published = true").bind(id) 
     }
-           .fetch_optional(db)
-.await
+    .fetch_optional(db)
+    .await
     .map_err(|e| {
-        println!("Failed to get item {}", e);
-        ()})?;
-    result.map(|r| (r, is_own)).ok_or(())
+        error!(error = %e, "Failed to get item");
+    })?;
+    let advert = result.ok_or(())?;
+    let is_own = is_admin || (user_id.is_some() && advert.user_id == user_id);
+    Ok((advert, is_own))
 }
 
-pub async fn get_main_page(
-    db: &Pool<Sqlite>,
-    limit: i64,
-    offset: i64,
-) -> Result<(Vec<Advert>, i64), ()> {
-    let result: Vec<Advert> = sqlx::query_as(
-        "SELECT * FROM adverts WHERE published = true ORDER BY ID DESC LIMIT ? OFFSET ?",
-    )
-    .bind(limit)
-    .bind(offset)
+#[instrument(skip_all)]
+pub async fn get_main_page(db: &Pool<Sqlite>, page: &PageRequest) -> Result<Page<Advert>, ()> {
+    let rows: Vec<Advert> = sqlx::query_as(&format!(
+        "SELECT * FROM adverts WHERE published = true AND {} ORDER BY id {} LIMIT ?",
+        page.condition(),
+        page.order()
+    ))
+    .bind(page.key())
+    .bind(page.fetch_limit())
     .fetch_all(db)
     .await
     .map_err(|e| {
-        println!("Failed to get adverts: {}", e);
-        ()
+        error!(error = %e, "Failed to get adverts");
     })?;
-
-    let total_count: i64 =
-        sqlx::query_scalar("SELECT COUNT(*) FROM adverts WHERE published = true")
-            .fetch_one(db)
-            .await
-            .map_err(|_| ())?;
-    Ok((result, total_count))
+    Ok(page.page(rows))
 }
 
-pub async fn get_mod_page(
-    db: &Pool<Sqlite>,
-    adverts_offset: i64,
-    adverts_limit: i64,
-    users_offset: i64,
-    users_limit: i64,
-) -> Result<((Vec<Advert>, i64), (Vec<User>, i64)), ()> {
-    let advert_result: Vec<Advert> =
-        sqlx::query_as("SELECT * FROM adverts ORDER BY ID DESC LIMIT ? OFFSET ?")
-            .bind(adverts_limit)
-            .bind(adverts_offset)
-            .fetch_all(db)
-            .await
-            .map_err(|_| ())?;
-
-    let adverts_total_count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM adverts")
-        .fetch_one(db)
-        .await
-        .map_err(|_| ())?;
-
-    let users_result: Vec<User> =
-        sqlx::query_as("SELECT * FROM users ORDER BY ID DESC LIMIT ? OFFSET ?")
-            .bind(users_limit)
-            .bind(users_offset)
-            .fetch_all(db)
-            .await
-            .map_err(|_| ())?;
-
-    let users_total_count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM users")
-        .fetch_one(db)
-        .await
-        .map_err(|e| {
-            eprintln!("Failed to get users count: {}", e);
-            ()
-        })?;
+#[instrument(skip_all)]
+pub async fn get_mod_adverts(db: &Pool<Sqlite>, page: &PageRequest) -> Result<Page<Advert>, ()> {
+    let rows: Vec<Advert> = sqlx::query_as(&format!(
+        "SELECT * FROM adverts WHERE {} ORDER BY id {} LIMIT ?",
+        page.condition(),
+        page.order()
+    ))
+    .bind(page.key())
+    .bind(page.fetch_limit())
+    .fetch_all(db)
+    .await
+    .map_err(|e| {
+        error!(error = %e, "Failed to get mod adverts");
+    })?;
+    Ok(page.page(rows))
+}
 
-    Ok((
-        (advert_result, adverts_total_count),
-        (users_result, users_total_count),
+#[instrument(skip_all)]
+pub async fn get_mod_users(db: &Pool<Sqlite>, page: &PageRequest) -> Result<Page<User>, ()> {
+    let rows: Vec<User> = sqlx::query_as(&format!(
+        "SELECT * FROM users WHERE {} ORDER BY id {} LIMIT ?",
+        page.condition(),
+        page.order()
     ))
+    .bind(page.key())
+    .bind(page.fetch_limit())
+    .fetch_all(db)
+    .await
+    .map_err(|e| {
+        error!(error = %e, "Failed to get mod users");
+    })?;
+    Ok(page.page(rows))
 }
 
+#[instrument(skip_all)]
 pub async fn toggle_advert_publish(
     db: &Pool<Sqlite>,
     advert_id: i64,
     published: bool,
 ) -> Result<(), ()> {
-    sqlx::query("UPDATE adverts SET published = ? WHERE id = ?")
-        .bind(published)
-        .bind(advert_id)
-        .execute(db)
-        .await
-        .map_err(|e| {
-            eprintln!("Failed to update advert publish: {}", e);
-            ()
-        })?;
+    // Publishing by a moderator means the advert was reviewed
+    sqlx::query(
+        r#"UPDATE adverts SET published = ?1,
+               moderation_rule = CASE WHEN ?1 THEN NULL ELSE moderation_rule END
+           WHERE id = ?2"#,
+    )
+    .bind(published)
+    .bind(advert_id)
+    .execute(db)
+    .await
+    .map_err(|e| {
+        error!(error = %e, "Failed to update advert publish");
+    })?;
+    Ok(())
+}
+
+/// Publish decision of a moderator, the first one is remembered for moderation stats
+#[instrument(skip_all)]
+pub async fn moderate_advert(db: &Pool<Sqlite>, advert_id: i64, published: bool) -> Result<(), ()> {
+    sqlx::query(
+        r#"UPDATE adverts SET published = ?1,
+               moderation_rule = CASE WHEN ?1 THEN NULL ELSE moderation_rule END,
+               moderated_at = COALESCE(moderated_at, ?2)
+           WHERE id = ?3"#,
+    )
+    .bind(published)
+    .bind(now())
+    .bind(advert_id)
+    .execute(db)
+    .await
+    .map_err(|e| {
+        error!(error = %e, "Failed to moderate advert");
+    })?;
     Ok(())
 }
 
+#[instrument(skip_all)]
 pub async fn toggle_user_active(db: &Pool<Sqlite>, user_id: i64, active: bool) -> Result<(), ()> {
-    sqlx::query("UPDATE users SET active = ? WHERE id = ?")
-        .bind(active)
-        .bind(user_id)
-        .execute(db)
-        .await
-        .map_err(|e| {
-            eprintln!("Failed to update users active: {}", e);
-            ()
-        })?;
+    // Deactivating an already active user is a permanent ban, activation lifts any ban.
+    // Users still pending activation stay pending, so the login doesn't call them banned.
+    sqlx::query(
+        r#"UPDATE users SET
+               banned = CASE WHEN ?1 THEN FALSE WHEN active THEN TRUE ELSE banned END,
+               banned_until = CASE WHEN ?1 OR active THEN NULL ELSE banned_until END,
+               ban_reason = CASE WHEN ?1 OR active THEN NULL ELSE ban_reason END,
+               was_active = CASE WHEN NOT ?1 AND active THEN TRUE ELSE was_active END,
+               active = ?1
+           WHERE id = ?2"#,
+    )
+    .bind(active)
+    .bind(user_id)
+    .execute(db)
+    .await
+    .map_err(|e| {
+        error!(error = %e, "Failed to update users active");
+    })?;
     Ok(())
 }
 
-pub async fn check_advert_belong_to_user(
+/// Bans the user until `until`, or forever if it is `None`
+#[instrument(skip_all)]
+pub async fn ban_user(
     db: &Pool<Sqlite>,
     user_id: i64,
-    advert_id: i64,
-) -> Result<bool, ()> {
-    let result: Option<i64> = sqlx::query_scalar(
-        "SELECT advert_id from users_adverts WHERE user_id = ? AND advert_id = ?",
+    until: Option<i64>,
+    reason: Option<&str>,
+) -> Result<(), ()> {
+    // Banning a banned user again keeps the state from before the first ban
+    sqlx::query(
+        r#"UPDATE users SET was_active = CASE WHEN banned THEN was_active ELSE active END,
+               active = FALSE, banned = TRUE, banned_until = ?, ban_reason = ?
+           WHERE id = ?"#,
     )
+    .bind(until)
+    .bind(reason)
     .bind(user_id)
-    .bind(advert_id)
-    .fetch_optional(db)
+    .execute(db)
     .await
     .map_err(|e| {
-        eprintln!("Failed to get user advert belong: {}", e);
-        ()
+        error!(error = %e, "Failed to ban user");
     })?;
+    Ok(())
+}
 
-    Ok(result.is_some())
+/// Lifts the ban of the user, restoring the state from before it. Returns whether the
+/// user is active now, users banned while pending activation stay pending.
+#[instrument(skip_all)]
+pub async fn unban_user(db: &Pool<Sqlite>, user_id: i64) -> Result<bool, ()> {
+    sqlx::query_scalar(
+        r#"UPDATE users SET active = CASE WHEN banned THEN was_active ELSE active END,
+               banned = FALSE, banned_until = NULL, ban_reason = NULL
+           WHERE id = ?
+           RETURNING active"#,
+    )
+    .bind(user_id)
+    .fetch_one(db)
+    .await
+    .map_err(|e| {
+        error!(error = %e, "Failed to unban user");
+    })
 }
 
-pub async fn get_user_adverts(
+/// Returns how many users were unbanned
+#[instrument(skip_all)]
+pub async fn lift_expired_bans(db: &Pool<Sqlite>) -> Result<u64, ()> {
+    let result = sqlx::query(
+        r#"UPDATE users SET active = was_active, banned = FALSE, banned_until = NULL,
+               ban_reason = NULL
+           WHERE banned = TRUE AND banned_until IS NOT NULL AND banned_until <= ?"#,
+    )
+    .bind(now())
+    .execute(db)
+    .await
+    .map_err(|e| {
+        error!(error = %e, "Failed to lift expired bans");
+    })?;
+    Ok(result.rows_affected())
+}
+
+#[instrument(skip_all)]
+pub async fn check_advert_belong_to_user(
     db: &Pool<Sqlite>,
     user_id: i64,
-    offset: i64,
-    limit: i64,
-) -> Result<(Vec<Advert>, i64), ()> {
-    let result: Vec<Advert> = sqlx::query_as("SELECT * FROM adverts a JOIN users_adverts u ON a.id = u.advert_id WHERE u.user_id = ? ORDER BY ID DESC LIMIT ? OFFSET ?")
+    advert_id: i64,
+) -> Result<bool, ()> {
+    let result: Option<i64> =
+        sqlx::query_scalar("SELECT id FROM adverts WHERE user_id = ? AND id = ?")
             .bind(user_id)
-            .bind(limit)
-            .bind(offset)
-    .fetch_all(db)
+            .bind(advert_id)
+            .fetch_optional(db)
+            .await
+            .map_err(|e| {
+                error!(error = %e, "Failed to get user advert belong");
+            })?;
+
+    Ok(result.is_some())
+}
+
+/// Records a view of the advert by `viewer`, returns false if the viewer has already been counted
+#[instrument(skip_all)]
+pub async fn record_advert_view(
+    db: &Pool<Sqlite>,
+    advert_id: i64,
+    viewer: &str,
+) -> Result<bool, ()> {
+    let mut tx = db.begin().await.map_err(|e| {
+        error!(error = %e, "Failed to start transaction");
+    })?;
+
+    let result = sqlx::query(
+        "INSERT OR IGNORE INTO advert_views(advert_id, viewer, viewed_at) VALUES (?, ?, ?)",
+    )
+    .bind(advert_id)
+    .bind(viewer)
+    .bind(now())
+    .execute(&mut *tx)
     .await
     .map_err(|e| {
-        println!("Failed to get user adverts: {}", e);
-        ()
+        error!(error = %e, "Failed to record advert view");
     })?;
+    if result.rows_affected() == 0 {
+        return Ok(false);
+    }
 
-    let total_count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM adverts a JOIN users_adverts u ON a.id = u.advert_id WHERE u.user_id = ? ")
-    .bind(user_id)
-    .fetch_one(db)
-    .await
-    .map_err(|_| ())?;
-    Ok((result, total_count))
+    sqlx::query("UPDATE adverts SET views = views + 1 WHERE id = ?")
+        .bind(advert_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| {
+            error!(error = %e, "Failed to increment advert views");
+        })?;
+
+    tx.commit().await.map_err(|e| {
+        error!(error = %e, "Failed to commit advert view");
+    })?;
+    Ok(true)
+}
+
+/// Recounts daily views for days from `first_day` on and prunes raw views before it
+#[instrument(skip_all)]
+pub async fn aggregate_advert_views(db: &Pool<Sqlite>, first_day: i64) -> Result<(), ()> {
+    let mut tx = db.begin().await.map_err(|e| {
+        error!(error = %e, "Failed to start transaction");
+    })?;
+
+    sqlx::query("INSERT INTO advert_daily_views(advert_id, day, views) SELECT advert_id, viewed_at / ?1, COUNT(*) FROM advert_views WHERE viewed_at >= ?2 GROUP BY advert_id, viewed_at / ?1 ON CONFLICT(advert_id, day) DO UPDATE SET views = excluded.views")
+        .bind(SECS_PER_DAY)
+        .bind(first_day * SECS_PER_DAY)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| {
+            error!(error = %e, "Failed to aggregate advert views");
+        })?;
+
+    sqlx::query("DELETE FROM advert_views WHERE viewed_at < ?")
+        .bind(first_day * SECS_PER_DAY)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| {
+            error!(error = %e, "Failed to prune advert views");
+        })?;
+
+    tx.commit().await.map_err(|e| {
+        error!(error = %e, "Failed to commit advert views aggregation");
+    })
+}
+
+/// Daily views of all adverts of the user from `first_day` on, as (advert id, day, views)
+#[instrument(skip_all)]
+pub async fn get_user_daily_views(
+    db: &Pool<Sqlite>,
+    user_id: i64,
+    first_day: i64,
+) -> Result<Vec<(i64, i64, i64)>, ()> {
+    sqlx::query_as("SELECT dv.advert_id, dv.day, dv.views FROM advert_daily_views dv JOIN adverts a ON dv.advert_id = a.id WHERE a.user_id = ? AND dv.day >= ?")
+        .bind(user_id)
+        .bind(first_day)
+        .fetch_all(db)
+        .await
+        .map_err(|e| {
+            error!(error = %e, "Failed to get daily views");
+        })
+}
+
+#[instrument(skip_all)]
+pub async fn get_advert_seller(db: &Pool<Sqlite>, advert_id: i64) -> Result<Option<Seller>, ()> {
+    sqlx::query_as("SELECT u.id, u.username, u.display_name, u.created_at FROM users u JOIN adverts a ON u.id = a.user_id WHERE a.id = ?")
+        .bind(advert_id)
+        .fetch_optional(db)
+        .await
+        .map_err(|e| {
+            error!(error = %e, "Failed to get advert seller");
+        })
+}
+
+/// Other published adverts of the seller, newest first
+#[instrument(skip_all)]
+pub async fn get_seller_adverts(
+    db: &Pool<Sqlite>,
+    user_id: i64,
+    exclude_advert_id: i64,
+    limit: i64,
+) -> Result<Vec<Advert>, ()> {
+    sqlx::query_as("SELECT * FROM adverts WHERE user_id = ? AND id != ? AND published = true ORDER BY id DESC LIMIT ?")
+        .bind(user_id)
+        .bind(exclude_advert_id)
+        .bind(limit)
+        .fetch_all(db)
+        .await
+        .map_err(|e| {
+            error!(error = %e, "Failed to get seller adverts");
+        })
+}
+
+/// Published adverts with titles matching any significant word of `title`, best matches first
+#[instrument(skip_all)]
+pub async fn get_similar_adverts(
+    db: &Pool<Sqlite>,
+    title: &str,
+    exclude_advert_id: i64,
+    limit: i64,
+) -> Result<Vec<Advert>, ()> {
+    // Every word is quoted so fts5 query syntax in titles is matched literally
+    let words = title
+        .split(|c: char| !c.is_alphanumeric())
+        .map(|word| word.to_lowercase())
+        .filter(|word| word.chars().count() > 2 && !SIMILAR_STOPWORDS.contains(&word.as_str()))
+        .map(|word| format!("\"{}\"", word))
+        .collect::<Vec<_>>();
+    if words.is_empty() {
+        return Ok(vec![]);
+    }
+    let query = format!("title : ({})", words.join(" OR "));
+
+    sqlx::query_as("SELECT a.* FROM adverts_fts f JOIN adverts a ON a.id = f.rowid WHERE adverts_fts MATCH ? AND a.id != ? AND a.published = true ORDER BY f.rank LIMIT ?")
+        .bind(query)
+        .bind(exclude_advert_id)
+        .bind(limit)
+        .fetch_all(db)
+        .await
+        .map_err(|e| {
+            error!(error = %e, "Failed to get similar adverts");
+        })
+}
+
+#[instrument(skip_all)]
+pub async fn get_user_adverts(
+    db: &Pool<Sqlite>,
+    user_id: i64,
+    page: &PageRequest,
+) -> Result<Page<Advert>, ()> {
+    let rows: Vec<Advert> = sqlx::query_as(&format!(
+        "SELECT * FROM adverts WHERE user_id = ? AND {} ORDER BY id {} LIMIT ?",
+        page.condition(),
+        page.order()
+    ))
+    .bind(user_id)
+    .bind(page.key())
+    .bind(page.fetch_limit())
+    .fetch_all(db)
+    .await
+    .map_err(|e| {
+        error!(error = %e, "Failed to get user adverts");
+    })?;
+    Ok(page.page(rows))
+}
+
+#[instrument(skip_all)]
+pub async fn get_user_settings(db: &Pool<Sqlite>, user_id: i64) -> Result<UserSettings, ()> {
+    sqlx::query_as("SELECT display_name, email, contact_phone FROM users WHERE id = ?")
+        .bind(user_id)
+        .fetch_one(db)
+        .await
+        .map_err(|e| {
+            error!(error = %e, "Failed to get user settings");
+        })
+}
+
+#[instrument(skip_all)]
+pub async fn update_user_settings(
+    db: &Pool<Sqlite>,
+    user_id: i64,
+    settings: &UserSettings,
+) -> Result<(), ()> {
+    sqlx::query("UPDATE users SET display_name = ?, email = ?, contact_phone = ? WHERE id = ?")
+        .bind(&settings.display_name)
+        .bind(&settings.email)
+        .bind(&settings.contact_phone)
+        .bind(user_id)
+        .execute(db)
+        .await
+        .map_err(|e| {
+            error!(error = %e, "Failed to update user settings");
+        })?;
+    Ok(())
+}
+
+#[instrument(skip_all)]
+pub async fn update_user_password(
+    db: &Pool<Sqlite>,
+    user_id: i64,
+    password: &str,
+) -> Result<(), ()> {
+    sqlx::query("UPDATE users SET password_hash = ? WHERE id = ?")
+        .bind(generate_hash(password))
+        .bind(user_id)
+        .execute(db)
+        .await
+        .map_err(|e| {
+            error!(error = %e, "Failed to update user password");
+        })?;
+    Ok(())
+}
+
+/// Deletes the user, their adverts go away with the user through the foreign key
+#[instrument(skip_all)]
+pub async fn delete_user(db: &Pool<Sqlite>, user_id: i64) -> Result<(), ()> {
+    let mut tx = db.begin().await.map_err(|e| {
+        error!(error = %e, "Failed to start transaction");
+    })?;
+    sqlx::query("DELETE FROM recovery_codes WHERE user_id = ?")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| {
+            error!(error = %e, "Failed to delete user recovery codes");
+        })?;
+    sqlx::query(
+        r#"UPDATE invites SET
+               created_by = CASE WHEN created_by = ? THEN NULL ELSE created_by END,
+               used_by = CASE WHEN used_by = ? THEN NULL ELSE used_by END
+           WHERE created_by = ? OR used_by = ?"#,
+    )
+    .bind(user_id)
+    .bind(user_id)
+    .bind(user_id)
+    .bind(user_id)
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| {
+        error!(error = %e, "Failed to detach user invites");
+    })?;
+    sqlx::query("DELETE FROM users_permissions WHERE user_id = ?")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| {
+            error!(error = %e, "Failed to delete user permissions");
+        })?;
+    sqlx::query("DELETE FROM users_groups WHERE user_id = ?")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| {
+            error!(error = %e, "Failed to delete user groups");
+        })?;
+    sqlx::query("DELETE FROM users WHERE id = ?")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| {
+            error!(error = %e, "Failed to delete user");
+        })?;
+    tx.commit().await.map_err(|e| {
+        error!(error = %e, "Failed to commit user deletion");
+    })
+}
+
+#[instrument(skip_all)]
+pub async fn get_login_lock(db: &Pool<Sqlite>, kind: &str, value: &str) -> Result<Option<i64>, ()> {
+    sqlx::query_scalar(
+        "SELECT locked_until FROM login_attempts WHERE kind = ? AND value = ? AND locked_until > ?",
+    )
+    .bind(kind)
+    .bind(value)
+    .bind(now())
+    .fetch_optional(db)
+    .await
+    .map_err(|e| {
+        error!(error = %e, "Failed to get login lock");
+    })
+}
+
+/// Returns number of recent failed attempts including this one
+#[instrument(skip_all)]
+pub async fn record_login_failure(db: &Pool<Sqlite>, kind: &str, value: &str) -> Result<i64, ()> {
+    let now = now();
+    sqlx::query_scalar(
+        r#"INSERT INTO login_attempts(kind, value, failures, last_failure) VALUES(?, ?, 1, ?)
+           ON CONFLICT(kind, value) DO UPDATE SET
+               failures = CASE WHEN last_failure < ? THEN 1 ELSE failures + 1 END,
+               last_failure = excluded.last_failure
+           RETURNING failures"#,
+    )
+    .bind(kind)
+    .bind(value)
+    .bind(now)
+    .bind(now - LOGIN_ATTEMPTS_WINDOW_SECS)
+    .fetch_one(db)
+    .await
+    .map_err(|e| {
+        error!(error = %e, "Failed to record login failure");
+    })
+}
+
+#[instrument(skip_all)]
+pub async fn lock_login(
+    db: &Pool<Sqlite>,
+    kind: &str,
+    value: &str,
+    locked_until: i64,
+) -> Result<(), ()> {
+    sqlx::query("UPDATE login_attempts SET locked_until = ? WHERE kind = ? AND value = ?")
+        .bind(locked_until)
+        .bind(kind)
+        .bind(value)
+        .execute(db)
+        .await
+        .map_err(|e| {
+            error!(error = %e, "Failed to lock login");
+        })?;
+    Ok(())
+}
+
+#[instrument(skip_all)]
+pub async fn reset_login_attempts(db: &Pool<Sqlite>, kind: &str, value: &str) -> Result<(), ()> {
+    sqlx::query("DELETE FROM login_attempts WHERE kind = ? AND value = ?")
+        .bind(kind)
+        .bind(value)
+        .execute(db)
+        .await
+        .map_err(|e| {
+            error!(error = %e, "Failed to reset login attempts");
+        })?;
+    Ok(())
+}
+
+#[instrument(skip_all)]
+pub async fn get_locked_logins(db: &Pool<Sqlite>) -> Result<Vec<LoginLock>, ()> {
+    sqlx::query_as(
+        "SELECT id, kind, value, failures, locked_until FROM login_attempts WHERE locked_until > ? ORDER BY locked_until DESC",
+    )
+    .bind(now())
+    .fetch_all(db)
+    .await
+    .map_err(|e| {
+        error!(error = %e, "Failed to get locked logins");
+    })
+}
+
+#[instrument(skip_all)]
+pub async fn unlock_login(db: &Pool<Sqlite>, id: i64) -> Result<(), ()> {
+    sqlx::query("DELETE FROM login_attempts WHERE id = ?")
+        .bind(id)
+        .execute(db)
+        .await
+        .map_err(|e| {
+            error!(error = %e, "Failed to unlock login");
+        })?;
+    Ok(())
+}
+
+/// Saves the secret with fresh recovery codes, `step` is the code used to confirm setup
+/// so it can't be replayed for login
+#[instrument(skip_all)]
+pub async fn enable_totp(
+    db: &Pool<Sqlite>,
+    user_id: i64,
+    secret: &str,
+    recovery_code_hashes: &[String],
+    step: i64,
+) -> Result<(), ()> {
+    let mut tx = db.begin().await.map_err(|e| {
+        error!(error = %e, "Failed to start transaction");
+    })?;
+    sqlx::query("UPDATE users SET totp_secret = ?, totp_last_step = ? WHERE id = ?")
+        .bind(secret)
+        .bind(step)
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| {
+            error!(error = %e, "Failed to enable totp");
+        })?;
+    sqlx::query("DELETE FROM recovery_codes WHERE user_id = ?")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| {
+            error!(error = %e, "Failed to remove old recovery codes");
+        })?;
+    for code_hash in recovery_code_hashes {
+        sqlx::query("INSERT INTO recovery_codes(user_id, code_hash) VALUES(?, ?)")
+            .bind(user_id)
+            .bind(code_hash)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| {
+                error!(error = %e, "Failed to save recovery code");
+            })?;
+    }
+    tx.commit().await.map_err(|e| {
+        error!(error = %e, "Failed to commit totp setup");
+    })
+}
+
+#[instrument(skip_all)]
+pub async fn disable_totp(db: &Pool<Sqlite>, user_id: i64) -> Result<(), ()> {
+    let mut tx = db.begin().await.map_err(|e| {
+        error!(error = %e, "Failed to start transaction");
+    })?;
+    sqlx::query("UPDATE users SET totp_secret = NULL, totp_last_step = NULL WHERE id = ?")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| {
+            error!(error = %e, "Failed to disable totp");
+        })?;
+    sqlx::query("DELETE FROM recovery_codes WHERE user_id = ?")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| {
+            error!(error = %e, "Failed to remove recovery codes");
+        })?;
+    tx.commit().await.map_err(|e| {
+        error!(error = %e, "Failed to commit totp removal");
+    })
+}
+
+/// Remembers the last accepted totp step, returns false if a newer one was already used
+#[instrument(skip_all)]
+pub async fn use_totp_step(db: &Pool<Sqlite>, user_id: i64, step: i64) -> Result<bool, ()> {
+    let result = sqlx::query(
+        "UPDATE users SET totp_last_step = ? WHERE id = ? AND (totp_last_step IS NULL OR totp_last_step < ?)",
+    )
+    .bind(step)
+    .bind(user_id)
+    .bind(step)
+    .execute(db)
+    .await
+    .map_err(|e| {
+        error!(error = %e, "Failed to update totp step");
+    })?;
+    Ok(result.rows_affected() == 1)
+}
+
+/// Marks recovery code as used, returns false if there is no such unused code
+#[instrument(skip_all)]
+pub async fn use_recovery_code(
+    db: &Pool<Sqlite>,
+    user_id: i64,
+    code_hash: &str,
+) -> Result<bool, ()> {
+    let result = sqlx::query(
+        "UPDATE recovery_codes SET used = TRUE WHERE user_id = ? AND code_hash = ? AND used = FALSE",
+    )
+    .bind(user_id)
+    .bind(code_hash)
+    .execute(db)
+    .await
+    .map_err(|e| {
+        error!(error = %e, "Failed to use recovery code");
+    })?;
+    Ok(result.rows_affected() > 0)
+}
+
+#[instrument(skip_all)]
+pub async fn count_recovery_codes(db: &Pool<Sqlite>, user_id: i64) -> Result<i64, ()> {
+    sqlx::query_scalar("SELECT COUNT(*) FROM recovery_codes WHERE user_id = ? AND used = FALSE")
+        .bind(user_id)
+        .fetch_one(db)
+        .await
+        .map_err(|e| {
+            error!(error = %e, "Failed to count recovery codes");
+        })
+}
+
+#[instrument(skip_all)]
+pub async fn get_groups(db: &Pool<Sqlite>) -> Result<Vec<Group>, ()> {
+    sqlx::query_as(
+        r#"SELECT g.id, g.name, COUNT(ug.user_id) AS members_count
+           FROM groups g
+           LEFT JOIN users_groups ug ON g.id = ug.group_id
+           GROUP BY g.id
+           ORDER BY g.name"#,
+    )
+    .fetch_all(db)
+    .await
+    .map_err(|e| {
+        error!(error = %e, "Failed to get groups");
+    })
+}
+
+#[instrument(skip_all)]
+pub async fn get_group(db: &Pool<Sqlite>, group_id: i64) -> Result<Option<Group>, ()> {
+    sqlx::query_as(
+        r#"SELECT g.id, g.name, COUNT(ug.user_id) AS members_count
+           FROM groups g
+           LEFT JOIN users_groups ug ON g.id = ug.group_id
+           WHERE g.id = ?
+           GROUP BY g.id"#,
+    )
+    .bind(group_id)
+    .fetch_optional(db)
+    .await
+    .map_err(|e| {
+        error!(error = %e, "Failed to get group");
+    })
+}
+
+#[instrument(skip_all)]
+pub async fn create_group(db: &Pool<Sqlite>, name: &str) -> Result<i64, ()> {
+    let result = sqlx::query("INSERT INTO groups(name) VALUES(?)")
+        .bind(name)
+        .execute(db)
+        .await
+        .map_err(|e| {
+            error!(error = %e, "Failed to create group");
+        })?;
+    Ok(result.last_insert_rowid())
+}
+
+/// All known permissions, marked if the group has them
+#[instrument(skip_all)]
+pub async fn get_group_permissions(
+    db: &Pool<Sqlite>,
+    group_id: i64,
+) -> Result<Vec<GroupPermission>, ()> {
+    sqlx::query_as(
+        r#"SELECT p.id, p.name, gp.group_id IS NOT NULL AS granted
+           FROM permissions p
+           LEFT JOIN groups_permissions gp ON p.id = gp.permission_id AND gp.group_id = ?
+           ORDER BY p.name"#,
+    )
+    .bind(group_id)
+    .fetch_all(db)
+    .await
+    .map_err(|e| {
+        error!(error = %e, "Failed to get group permissions");
+    })
+}
+
+#[instrument(skip_all)]
+pub async fn grant_group_permission(
+    db: &Pool<Sqlite>,
+    group_id: i64,
+    permission_id: i64,
+) -> Result<(), ()> {
+    sqlx::query("INSERT OR IGNORE INTO groups_permissions(group_id, permission_id) VALUES(?, ?)")
+        .bind(group_id)
+        .bind(permission_id)
+        .execute(db)
+        .await
+        .map_err(|e| {
+            error!(error = %e, "Failed to grant group permission");
+        })?;
+    Ok(())
+}
+
+#[instrument(skip_all)]
+pub async fn revoke_group_permission(
+    db: &Pool<Sqlite>,
+    group_id: i64,
+    permission_id: i64,
+) -> Result<(), ()> {
+    sqlx::query("DELETE FROM groups_permissions WHERE group_id = ? AND permission_id = ?")
+        .bind(group_id)
+        .bind(permission_id)
+        .execute(db)
+        .await
+        .map_err(|e| {
+            error!(error = %e, "Failed to revoke group permission");
+        })?;
+    Ok(())
+}
+
+#[instrument(skip_all)]
+pub async fn get_group_members(db: &Pool<Sqlite>, group_id: i64) -> Result<Vec<GroupMember>, ()> {
+    sqlx::query_as(
+        r#"SELECT u.id, u.username
+           FROM users u
+           JOIN users_groups ug ON u.id = ug.user_id
+           WHERE ug.group_id = ?
+           ORDER BY u.username"#,
+    )
+    .bind(group_id)
+    .fetch_all(db)
+    .await
+    .map_err(|e| {
+        error!(error = %e, "Failed to get group members");
+    })
+}
+
+/// Returns false if there is no user with such name
+#[instrument(skip_all)]
+pub async fn add_user_to_group(
+    db: &Pool<Sqlite>,
+    group_id: i64,
+    username: &str,
+) -> Result<bool, ()> {
+    let result = sqlx::query(
+        r#"INSERT OR IGNORE INTO users_groups(user_id, group_id)
+           SELECT id, ? FROM users WHERE username = ?"#,
+    )
+    .bind(group_id)
+    .bind(username)
+    .execute(db)
+    .await
+    .map_err(|e| {
+        error!(error = %e, "Failed to add user to group");
+    })?;
+    if result.rows_affected() > 0 {
+        return Ok(true);
+    }
+    let user_exists: Option<i64> = sqlx::query_scalar("SELECT id FROM users WHERE username = ?")
+        .bind(username)
+        .fetch_optional(db)
+        .await
+        .map_err(|e| {
+            error!(error = %e, "Failed to find user");
+        })?;
+    Ok(user_exists.is_some())
+}
+
+#[instrument(skip_all)]
+pub async fn remove_user_from_group(
+    db: &Pool<Sqlite>,
+    group_id: i64,
+    user_id: i64,
+) -> Result<(), ()> {
+    sqlx::query("DELETE FROM users_groups WHERE group_id = ? AND user_id = ?")
+        .bind(group_id)
+        .bind(user_id)
+        .execute(db)
+        .await
+        .map_err(|e| {
+            error!(error = %e, "Failed to remove user from group");
+        })?;
+    Ok(())
+}
+
+/// Any user regardless of activation, unlike the auth backend lookup
+#[instrument(skip_all)]
+pub async fn get_user(db: &Pool<Sqlite>, user_id: i64) -> Result<Option<User>, ()> {
+    sqlx::query_as("SELECT * FROM users WHERE id = ?")
+        .bind(user_id)
+        .fetch_optional(db)
+        .await
+        .map_err(|e| {
+            error!(error = %e, "Failed to get user");
+        })
+}
+
+/// All known permissions with what the user gets from groups and personal overrides
+#[instrument(skip_all)]
+pub async fn get_user_permissions(
+    db: &Pool<Sqlite>,
+    user_id: i64,
+) -> Result<Vec<UserPermission>, ()> {
+    sqlx::query_as(
+        r#"SELECT p.id, p.name,
+               EXISTS(SELECT 1 FROM users_groups ug
+                      JOIN groups_permissions gp ON ug.group_id = gp.group_id
+                      WHERE ug.user_id = ? AND gp.permission_id = p.id) AS from_groups,
+               up.granted AS override_granted
+           FROM permissions p
+           LEFT JOIN users_permissions up ON p.id = up.permission_id AND up.user_id = ?
+           ORDER BY p.name"#,
+    )
+    .bind(user_id)
+    .bind(user_id)
+    .fetch_all(db)
+    .await
+    .map_err(|e| {
+        error!(error = %e, "Failed to get user permissions");
+    })
+}
+
+/// Grants or denies the permission to the user, `None` falls back to group permissions
+#[instrument(skip_all)]
+pub async fn set_user_permission(
+    db: &Pool<Sqlite>,
+    user_id: i64,
+    permission_id: i64,
+    granted: Option<bool>,
+) -> Result<(), ()> {
+    let query = match granted {
+        Some(granted) => sqlx::query(
+            r#"INSERT INTO users_permissions(user_id, permission_id, granted) VALUES(?, ?, ?)
+               ON CONFLICT(user_id, permission_id) DO UPDATE SET granted = excluded.granted"#,
+        )
+        .bind(user_id)
+        .bind(permission_id)
+        .bind(granted),
+        None => {
+            sqlx::query("DELETE FROM users_permissions WHERE user_id = ? AND permission_id = ?")
+                .bind(user_id)
+                .bind(permission_id)
+        }
+    };
+    query.execute(db).await.map_err(|e| {
+        error!(error = %e, "Failed to set user permission");
+    })?;
+    Ok(())
+}
+
+#[instrument(skip_all)]
+pub async fn get_invites(db: &Pool<Sqlite>) -> Result<Vec<Invite>, ()> {
+    sqlx::query_as(
+        r#"SELECT i.id, i.code, creator.username AS created_by, i.created_at, i.expires_at,
+               redeemer.username AS used_by, i.used_at,
+               i.expires_at IS NOT NULL AND i.expires_at <= ? AS expired
+           FROM invites i
+           LEFT JOIN users creator ON i.created_by = creator.id
+           LEFT JOIN users redeemer ON i.used_by = redeemer.id
+           ORDER BY i.id DESC"#,
+    )
+    .bind(now())
+    .fetch_all(db)
+    .await
+    .map_err(|e| {
+        error!(error = %e, "Failed to get invites");
+    })
+}
+
+#[instrument(skip_all)]
+pub async fn create_invite(
+    db: &Pool<Sqlite>,
+    code: &str,
+    created_by: i64,
+    expires_at: Option<i64>,
+) -> Result<(), ()> {
+    sqlx::query("INSERT INTO invites(code, created_by, created_at, expires_at) VALUES(?, ?, ?, ?)")
+        .bind(code)
+        .bind(created_by)
+        .bind(now())
+        .bind(expires_at)
+        .execute(db)
+        .await
+        .map_err(|e| {
+            error!(error = %e, "Failed to create invite");
+        })?;
+    Ok(())
+}
+
+/// Used invites are kept as a record of who invited whom
+#[instrument(skip_all)]
+pub async fn delete_unused_invite(db: &Pool<Sqlite>, invite_id: i64) -> Result<(), ()> {
+    sqlx::query("DELETE FROM invites WHERE id = ? AND used_by IS NULL")
+        .bind(invite_id)
+        .execute(db)
+        .await
+        .map_err(|e| {
+            error!(error = %e, "Failed to delete invite");
+        })?;
+    Ok(())
+}
+
+/// Files or updates the user's report, returns how many distinct users have pending
+/// reports on the advert. Reports already reviewed by a moderator are not reopened.
+#[instrument(skip_all)]
+pub async fn create_report(
+    db: &Pool<Sqlite>,
+    advert_id: i64,
+    reporter_id: i64,
+    reason: ReportReason,
+    details: Option<&str>,
+) -> Result<i64, ()> {
+    let mut tx = db.begin().await.map_err(|e| {
+        error!(error = %e, "Failed to start transaction");
+    })?;
+    sqlx::query(
+        r#"INSERT INTO reports(advert_id, reporter_id, reason, details, created_at)
+           VALUES(?, ?, ?, ?, ?)
+           ON CONFLICT(advert_id, reporter_id) DO UPDATE SET
+               reason = excluded.reason,
+               details = excluded.details,
+               created_at = excluded.created_at
+           WHERE resolved = FALSE"#,
+    )
+    .bind(advert_id)
+    .bind(reporter_id)
+    .bind(reason)
+    .bind(details)
+    .bind(now())
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| {
+        error!(error = %e, "Failed to create report");
+    })?;
+
+    let count =
+        sqlx::query_scalar("SELECT COUNT(*) FROM reports WHERE advert_id = ? AND resolved = FALSE")
+            .bind(advert_id)
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(|e| {
+                error!(error = %e, "Failed to count reports");
+            })?;
+    tx.commit().await.map_err(|e| {
+        error!(error = %e, "Failed to commit report");
+    })?;
+    Ok(count)
+}
+
+/// Adverts with pending reports, most reported first
+#[instrument(skip_all)]
+pub async fn get_reported_adverts(db: &Pool<Sqlite>) -> Result<Vec<ReportedAdvert>, ()> {
+    sqlx::query_as(
+        r#"SELECT a.id, a.title, a.published,
+               COUNT(r.id) AS reports_count,
+               GROUP_CONCAT(DISTINCT r.reason) AS reasons,
+               GROUP_CONCAT(r.details, ' | ') AS details
+           FROM reports r
+           JOIN adverts a ON r.advert_id = a.id
+           WHERE r.resolved = FALSE
+           GROUP BY a.id
+           ORDER BY reports_count DESC, MAX(r.created_at) DESC"#,
+    )
+    .fetch_all(db)
+    .await
+    .map_err(|e| {
+        error!(error = %e, "Failed to get reported adverts");
+    })
+}
+
+#[instrument(skip_all)]
+pub async fn resolve_reports(db: &Pool<Sqlite>, advert_id: i64) -> Result<(), ()> {
+    sqlx::query("UPDATE reports SET resolved = TRUE WHERE advert_id = ? AND resolved = FALSE")
+        .bind(advert_id)
+        .execute(db)
+        .await
+        .map_err(|e| {
+            error!(error = %e, "Failed to resolve reports");
+        })?;
+    Ok(())
+}
+
+#[instrument(skip_all)]
+pub async fn get_filter_rules(db: &Pool<Sqlite>) -> Result<Vec<FilterRule>, ()> {
+    sqlx::query_as("SELECT * FROM filter_rules ORDER BY id")
+        .fetch_all(db)
+        .await
+        .map_err(|e| {
+            error!(error = %e, "Failed to get filter rules");
+        })
+}
+
+#[instrument(skip_all)]
+pub async fn create_filter_rule(
+    db: &Pool<Sqlite>,
+    kind: FilterRuleKind,
+    pattern: &str,
+    action: FilterRuleAction,
+) -> Result<(), ()> {
+    sqlx::query("INSERT INTO filter_rules(kind, pattern, action) VALUES(?, ?, ?)")
+        .bind(kind)
+        .bind(pattern)
+        .bind(action)
+        .execute(db)
+        .await
+        .map_err(|e| {
+            error!(error = %e, "Failed to create filter rule");
+        })?;
+    Ok(())
+}
+
+#[instrument(skip_all)]
+pub async fn delete_filter_rule(db: &Pool<Sqlite>, rule_id: i64) -> Result<(), ()> {
+    sqlx::query("DELETE FROM filter_rules WHERE id = ?")
+        .bind(rule_id)
+        .execute(db)
+        .await
+        .map_err(|e| {
+            error!(error = %e, "Failed to delete filter rule");
+        })?;
+    Ok(())
+}
+
+/// Looks for another advert with the same normalized text, `exclude` skips the edited one
+#[instrument(skip_all)]
+pub async fn has_duplicate_advert(
+    db: &Pool<Sqlite>,
+    content_hash: &str,
+    exclude: Option<i64>,
+) -> Result<bool, ()> {
+    sqlx::query_scalar(
+        "SELECT EXISTS(SELECT 1 FROM adverts WHERE content_hash = ? AND id IS NOT ?)",
+    )
+    .bind(content_hash)
+    .bind(exclude)
+    .fetch_one(db)
+    .await
+    .map_err(|e| {
+        error!(error = %e, "Failed to check duplicate adverts");
+    })
+}
+
+/// Fingerprints of the newest adverts, SQLite can't count differing bits so they are
+/// compared in Rust
+#[instrument(skip_all)]
+pub async fn get_recent_advert_fingerprints(
+    db: &Pool<Sqlite>,
+    limit: i64,
+) -> Result<Vec<AdvertFingerprint>, ()> {
+    sqlx::query_as(
+        r#"SELECT id, fingerprint
+           FROM adverts
+           WHERE fingerprint IS NOT NULL
+           ORDER BY id DESC
+           LIMIT ?"#,
+    )
+    .bind(limit)
+    .fetch_all(db)
+    .await
+    .map_err(|e| {
+        error!(error = %e, "Failed to get advert fingerprints");
+    })
+}
+
+/// Fingerprints of published adverts of the user
+#[instrument(skip_all)]
+pub async fn get_user_advert_fingerprints(
+    db: &Pool<Sqlite>,
+    user_id: i64,
+) -> Result<Vec<AdvertFingerprint>, ()> {
+    sqlx::query_as(
+        r#"SELECT id, fingerprint
+           FROM adverts
+           WHERE user_id = ? AND published AND fingerprint IS NOT NULL"#,
+    )
+    .bind(user_id)
+    .fetch_all(db)
+    .await
+    .map_err(|e| {
+        error!(error = %e, "Failed to get user advert fingerprints");
+    })
+}
+
+/// Adverts stored before fingerprints were computed
+#[instrument(skip_all)]
+pub async fn get_adverts_without_fingerprint(
+    db: &Pool<Sqlite>,
+    limit: i64,
+) -> Result<Vec<Advert>, ()> {
+    sqlx::query_as("SELECT * FROM adverts WHERE fingerprint IS NULL ORDER BY id LIMIT ?")
+        .bind(limit)
+        .fetch_all(db)
+        .await
+        .map_err(|e| {
+            error!(error = %e, "Failed to get adverts without fingerprint");
+        })
+}
+
+#[instrument(skip_all)]
+pub async fn set_advert_fingerprints(
+    db: &Pool<Sqlite>,
+    fingerprints: &[AdvertFingerprint],
+) -> Result<(), ()> {
+    let mut tx = db.begin().await.map_err(|e| {
+        error!(error = %e, "Failed to start transaction");
+    })?;
+    for advert in fingerprints {
+        sqlx::query("UPDATE adverts SET fingerprint = ? WHERE id = ?")
+            .bind(advert.fingerprint)
+            .bind(advert.id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| {
+                error!(error = %e, "Failed to set advert fingerprint");
+            })?;
+    }
+    tx.commit().await.map_err(|e| {
+        error!(error = %e, "Failed to commit transaction");
+    })
+}
+
+#[instrument(skip_all)]
+pub async fn get_user_stats(db: &Pool<Sqlite>) -> Result<UserStats, ()> {
+    sqlx::query_as(
+        r#"SELECT COUNT(*) AS total,
+               COUNT(*) FILTER (WHERE active AND NOT banned) AS active,
+               COUNT(*) FILTER (WHERE NOT active AND NOT banned) AS pending,
+               COUNT(*) FILTER (WHERE banned) AS banned
+           FROM users"#,
+    )
+    .fetch_one(db)
+    .await
+    .map_err(|e| {
+        error!(error = %e, "Failed to get user stats");
+    })
+}
+
+#[instrument(skip_all)]
+pub async fn get_advert_stats(db: &Pool<Sqlite>) -> Result<AdvertStats, ()> {
+    sqlx::query_as(
+        r#"SELECT COUNT(*) AS total,
+               COUNT(*) FILTER (WHERE published) AS published,
+               COUNT(*) FILTER (WHERE NOT published AND moderation_rule IS NOT NULL) AS held,
+               COUNT(*) FILTER (WHERE NOT published AND moderation_rule IS NULL) AS unpublished,
+               COUNT(*) FILTER (WHERE NOT published AND moderated_at IS NULL) AS awaiting_review,
+               COUNT(*) FILTER (WHERE NOT published AND (moderation_rule IS NOT NULL OR moderated_at IS NULL)) AS queued,
+               (SELECT COUNT(DISTINCT advert_id) FROM reports WHERE NOT resolved) AS reported
+           FROM adverts"#,
+    )
+    .fetch_one(db)
+    .await
+    .map_err(|e| {
+        error!(error = %e, "Failed to get advert stats");
+    })
+}
+
+/// Registrations per day from `first_day` on, as (day, count)
+#[instrument(skip_all)]
+pub async fn get_daily_registrations(
+    db: &Pool<Sqlite>,
+    first_day: i64,
+) -> Result<Vec<(i64, i64)>, ()> {
+    sqlx::query_as(
+        "SELECT created_at / ?1 AS day, COUNT(*) FROM users WHERE created_at >= ?2 GROUP BY day",
+    )
+    .bind(SECS_PER_DAY)
+    .bind(first_day * SECS_PER_DAY)
+    .fetch_all(db)
+    .await
+    .map_err(|e| {
+        error!(error = %e, "Failed to get daily registrations");
+    })
+}
+
+/// New adverts per day from `first_day` on, as (day, count)
+#[instrument(skip_all)]
+pub async fn get_daily_postings(db: &Pool<Sqlite>, first_day: i64) -> Result<Vec<(i64, i64)>, ()> {
+    sqlx::query_as(
+        "SELECT created_at / ?1 AS day, COUNT(*) FROM adverts WHERE created_at >= ?2 GROUP BY day",
+    )
+    .bind(SECS_PER_DAY)
+    .bind(first_day * SECS_PER_DAY)
+    .fetch_all(db)
+    .await
+    .map_err(|e| {
+        error!(error = %e, "Failed to get daily postings");
+    })
+}
+
+#[instrument(skip_all)]
+pub async fn get_moderation_latency(db: &Pool<Sqlite>) -> Result<ModerationLatency, ()> {
+    // Adverts created before creation times were stored have no meaningful latency
+    sqlx::query_as(
+        r#"SELECT COUNT(*) AS reviewed,
+               CAST(COALESCE(AVG(moderated_at - created_at), 0) AS INTEGER) AS average_secs,
+               COALESCE(MAX(moderated_at - created_at), 0) AS max_secs
+           FROM adverts WHERE moderated_at IS NOT NULL AND created_at > 0"#,
+    )
+    .fetch_one(db)
+    .await
+    .map_err(|e| {
+        error!(error = %e, "Failed to get moderation latency");
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    use super::*;
+
+    /// Migrated in-memory database, a single connection so every query sees the same one
+    async fn test_db() -> Pool<Sqlite> {
+        let options = SqliteConnectOptions::from_str("sqlite::memory:")
+            .unwrap()
+            .foreign_keys(true);
+        let db = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect_with(options)
+            .await
+            .unwrap();
+        MIGRATOR.run(&db).await.unwrap();
+        db
+    }
+
+    /// Makes the statement the trigger is set on fail, like a full disk or a lost connection
+    async fn fail_on(db: &Pool<Sqlite>, event: &str) {
+        sqlx::query(&format!(
+            "CREATE TRIGGER fail {} BEGIN SELECT RAISE(ABORT, 'failed'); END",
+            event
+        ))
+        .execute(db)
+        .await
+        .unwrap();
+    }
+
+    async fn count(db: &Pool<Sqlite>, query: &str) -> i64 {
+        sqlx::query_scalar(query).fetch_one(db).await.unwrap()
+    }
+
+    async fn new_user(db: &Pool<Sqlite>, username: &str) -> i64 {
+        create_new_user(db, username, "password", &["users"], true, None)
+            .await
+            .unwrap();
+        sqlx::query_scalar("SELECT id FROM users WHERE username = ?")
+            .bind(username)
+            .fetch_one(db)
+            .await
+            .unwrap()
+    }
+
+    async fn active_and_banned(db: &Pool<Sqlite>, user_id: i64) -> (bool, bool) {
+        sqlx::query_as("SELECT active, banned FROM users WHERE id = ?")
+            .bind(user_id)
+            .fetch_one(db)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn expired_ban_of_pending_user_keeps_them_pending() {
+        let db = test_db().await;
+        create_new_user(&db, "pending", "password", &["users"], false, None)
+            .await
+            .unwrap();
+        let pending = 1;
+        let active = new_user(&db, "active").await;
+        for user_id in [pending, active] {
+            ban_user(&db, user_id, Some(now() - 1), None).await.unwrap();
+        }
+
+        assert_eq!(lift_expired_bans(&db).await.unwrap(), 2);
+        assert_eq!(active_and_banned(&db, pending).await, (false, false));
+        assert_eq!(active_and_banned(&db, active).await, (true, false));
+    }
+
+    #[tokio::test]
+    async fn unban_restores_state_before_the_ban() {
+        let db = test_db().await;
+        create_new_user(&db, "pending", "password", &["users"], false, None)
+            .await
+            .unwrap();
+        let pending = 1;
+        let active = new_user(&db, "active").await;
+        for user_id in [pending, active] {
+            ban_user(&db, user_id, Some(now() + 60), None)
+                .await
+                .unwrap();
+            // A second ban must not take the first one as the state to restore
+            ban_user(&db, user_id, None, Some("again")).await.unwrap();
+        }
+
+        assert!(!unban_user(&db, pending).await.unwrap());
+        assert!(unban_user(&db, active).await.unwrap());
+        assert_eq!(active_and_banned(&db, pending).await, (false, false));
+        assert_eq!(active_and_banned(&db, active).await, (true, false));
+    }
+
+    #[tokio::test]
+    async fn recovery_codes_are_single_use() {
+        let db = test_db().await;
+        let user_id = new_user(&db, "user").await;
+        let codes = ["first".to_string(), "second".to_string()];
+        enable_totp(&db, user_id, "secret", &codes, 1)
+            .await
+            .unwrap();
+
+        assert!(use_recovery_code(&db, user_id, "first").await.unwrap());
+        assert!(!use_recovery_code(&db, user_id, "first").await.unwrap());
+        assert!(!use_recovery_code(&db, user_id, "unknown").await.unwrap());
+        assert_eq!(count_recovery_codes(&db, user_id).await.unwrap(), 1);
+        // Codes belong to their user only
+        let other_id = new_user(&db, "other").await;
+        assert!(!use_recovery_code(&db, other_id, "second").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn totp_steps_are_single_use() {
+        let db = test_db().await;
+        let user_id = new_user(&db, "user").await;
+        enable_totp(&db, user_id, "secret", &[], 10).await.unwrap();
+
+        assert!(!use_totp_step(&db, user_id, 10).await.unwrap());
+        assert!(use_totp_step(&db, user_id, 11).await.unwrap());
+        assert!(!use_totp_step(&db, user_id, 11).await.unwrap());
+        assert!(!use_totp_step(&db, user_id, 9).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn create_user_rolls_back_on_failed_invite() {
+        let db = test_db().await;
+        sqlx::query("INSERT INTO invites(code, created_at) VALUES('code', 0)")
+            .execute(&db)
+            .await
+            .unwrap();
+        fail_on(&db, "BEFORE UPDATE ON invites").await;
+
+        let result = create_new_user(
+            &db,
+            "user",
+            "password",
+            &["users", "admins"],
+            true,
+            Some("code"),
+        )
+        .await;
+        assert!(matches!(result, Err(CreateUserError::Internal)));
+        assert_eq!(count(&db, "SELECT COUNT(*) FROM users").await, 0);
+        assert_eq!(count(&db, "SELECT COUNT(*) FROM users_groups").await, 0);
+    }
+
+    #[tokio::test]
+    async fn create_user_fails_on_unknown_group() {
+        let db = test_db().await;
+
+        let result =
+            create_new_user(&db, "user", "password", &["users", "nobody"], true, None).await;
+        assert!(matches!(result, Err(CreateUserError::Internal)));
+        assert_eq!(count(&db, "SELECT COUNT(*) FROM users").await, 0);
+        assert_eq!(count(&db, "SELECT COUNT(*) FROM users_groups").await, 0);
+    }
+
+    #[tokio::test]
+    async fn create_advert_rolls_back_on_failed_insert() {
+        let db = test_db().await;
+        let user_id = new_user(&db, "user").await;
+        fail_on(&db, "AFTER INSERT ON adverts").await;
+
+        let moderation = AdvertModeration {
+            published: true,
+            moderation_rule: None,
+            content_hash: "hash".to_string(),
+            fingerprint: 0,
+        };
+        let result = create_new_advert(
+            &db,
+            user_id,
+            "Title",
+            "Content",
+            &moderation,
+            &AdvertQuota::default(),
+        )
+        .await;
+        assert!(matches!(result, Err(CreateAdvertError::Internal)));
+        assert_eq!(count(&db, "SELECT COUNT(*) FROM adverts").await, 0);
+        assert_eq!(
+            count(
+                &db,
+                "SELECT COUNT(*) FROM adverts_fts WHERE adverts_fts MATCH 'title'"
+            )
+            .await,
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn advert_quota_is_counted_under_write_lock() {
+        // A database file, the in-memory one of the other tests has a single connection
+        let path = std::env::temp_dir().join(format!("simple_bulletin_{}.db", std::process::id()));
+        let db_url = path.to_str().unwrap();
+        let db = create_db(db_url).await.unwrap();
+        let user_id = new_user(&db, "user").await;
+
+        let moderation = AdvertModeration {
+            published: true,
+            moderation_rule: None,
+            content_hash: "hash".to_string(),
+            fingerprint: 0,
+        };
+        let quota = AdvertQuota {
+            max_published: 1,
+            ..AdvertQuota::default()
+        };
+        // Another writer holds the lock while the advert is being created
+        let mut other = ImmediateTransaction::begin(&db).await.unwrap();
+        let creating = tokio::spawn({
+            let db = db.clone();
+            async move {
+                create_new_advert(&db, user_id, "Second", "Content", &moderation, &quota).await
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!creating.is_finished());
+        sqlx::query("INSERT INTO adverts(user_id, title, content, published) VALUES(?, 'First', 'Content', TRUE)")
+            .bind(user_id)
+            .execute(&mut *other)
+            .await
+            .unwrap();
+        other.commit().await.unwrap();
+        // The quota is counted after the other advert, not from a stale snapshot
+        let result = creating.await.unwrap();
+        let published = count(&db, "SELECT COUNT(*) FROM adverts").await;
+        db.close().await;
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", db_url, suffix));
+        }
+
+        assert!(
+            matches!(result, Err(CreateAdvertError::PublishedQuota(1))),
+            "{:?}",
+            result
+        );
+        assert_eq!(published, 1);
+    }
+
+    #[tokio::test]
+    async fn rejected_advert_releases_transaction() {
+        let db = test_db().await;
+        let user_id = new_user(&db, "user").await;
+        let moderation = AdvertModeration {
+            published: true,
+            moderation_rule: None,
+            content_hash: "hash".to_string(),
+            fingerprint: 0,
+        };
+        let quota = AdvertQuota {
+            max_published: 1,
+            ..AdvertQuota::default()
+        };
+
+        create_new_advert(&db, user_id, "First", "Content", &moderation, &quota)
+            .await
+            .unwrap();
+        let result =
+            create_new_advert(&db, user_id, "Second", "Content", &moderation, &quota).await;
+        assert!(matches!(result, Err(CreateAdvertError::PublishedQuota(1))));
+        // The single connection would still be inside the transaction otherwise
+        let tx = ImmediateTransaction::begin(&db).await.unwrap();
+        drop(tx);
+        ImmediateTransaction::begin(&db).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn delete_user_rolls_back_on_failed_delete() {
+        let db = test_db().await;
+        let user_id = new_user(&db, "user").await;
+        sqlx::query("INSERT INTO recovery_codes(user_id, code_hash) VALUES(?, 'hash')")
+            .bind(user_id)
+            .execute(&db)
+            .await
+            .unwrap();
+        sqlx::query(
+            "INSERT INTO users_permissions(user_id, permission_id, granted) VALUES(?, 1, TRUE)",
+        )
+        .bind(user_id)
+        .execute(&db)
+        .await
+        .unwrap();
+        sqlx::query("INSERT INTO invites(code, created_by, created_at) VALUES('code', ?, 0)")
+            .bind(user_id)
+            .execute(&db)
+            .await
+            .unwrap();
+        fail_on(&db, "BEFORE DELETE ON users").await;
+
+        assert!(delete_user(&db, user_id).await.is_err());
+        assert_eq!(count(&db, "SELECT COUNT(*) FROM users").await, 1);
+        assert_eq!(count(&db, "SELECT COUNT(*) FROM recovery_codes").await, 1);
+        assert_eq!(
+            count(&db, "SELECT COUNT(*) FROM users_permissions").await,
+            1
+        );
+        assert_eq!(count(&db, "SELECT COUNT(*) FROM users_groups").await, 1);
+        assert_eq!(
+            count(
+                &db,
+                "SELECT COUNT(*) FROM invites WHERE created_by IS NOT NULL"
+            )
+            .await,
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn enable_totp_rolls_back_on_failed_recovery_code() {
+        let db = test_db().await;
+        let user_id = new_user(&db, "user").await;
+        enable_totp(&db, user_id, "old", &["old".to_string()], 1)
+            .await
+            .unwrap();
+        fail_on(
+            &db,
+            "BEFORE INSERT ON recovery_codes WHEN new.code_hash = 'new2'",
+        )
+        .await;
+
+        let codes = ["new1".to_string(), "new2".to_string()];
+        assert!(enable_totp(&db, user_id, "new", &codes, 2).await.is_err());
+        let secret: Option<String> = sqlx::query_scalar("SELECT totp_secret FROM users")
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        assert_eq!(secret.as_deref(), Some("old"));
+        let codes: Vec<String> = sqlx::query_scalar("SELECT code_hash FROM recovery_codes")
+            .fetch_all(&db)
+            .await
+            .unwrap();
+        assert_eq!(codes, ["old"]);
+    }
 }
@@ -0,0 +1,29 @@
+//! Custom askama filters, import with `use crate::filters;` next to the template struct.
+
+use chrono::DateTime;
+
+pub fn format_datetime(timestamp: i64) -> String {
+    DateTime::from_timestamp(timestamp, 0)
+        .map(|datetime| datetime.format("%Y-%m-%d %H:%M UTC").to_string())
+        .unwrap_or_default()
+}
+
+pub fn datetime(timestamp: &i64) -> askama::Result<String> {
+    Ok(format_datetime(*timestamp))
+}
+
+/// Rough human readable duration like `2d 3h` or `15m`
+pub fn format_duration(secs: i64) -> String {
+    let (days, hours, minutes) = (secs / 86400, secs % 86400 / 3600, secs % 3600 / 60);
+    if days > 0 {
+        format!("{}d {}h", days, hours)
+    } else if hours > 0 {
+        format!("{}h {}m", hours, minutes)
+    } else {
+        format!("{}m", minutes)
+    }
+}
+
+pub fn duration(secs: &i64) -> askama::Result<String> {
+    Ok(format_duration(*secs))
+}
@@ -0,0 +1,123 @@
+//! Periodic background jobs. Every job runs on its own interval, a failed or panicked run
+//! is logged and the job goes on with the next tick. On shutdown the supervisor lets
+//! running jobs finish their current run and stops them.
+
+use std::{future::Future, pin::Pin, time::Duration};
+
+use tokio::{sync::watch, task::JoinSet, time::MissedTickBehavior};
+use tracing::{error, info, warn};
+
+use crate::{content_filter, db, models::AdvertFingerprint, view_stats, AppState};
+
+type JobFuture = Pin<Box<dyn Future<Output = Result<(), ()>> + Send>>;
+
+pub struct Job {
+    name: &'static str,
+    interval: Duration,
+    run: fn(AppState) -> JobFuture,
+}
+
+/// Adverts fingerprinted per run of the backfill
+const FINGERPRINT_BATCH: i64 = 500;
+
+pub const JOBS: [Job; 3] = [
+    Job {
+        name: "lift_expired_bans",
+        interval: Duration::from_secs(60),
+        run: lift_expired_bans,
+    },
+    Job {
+        name: "aggregate_advert_views",
+        interval: Duration::from_secs(10 * 60),
+        run: aggregate_advert_views,
+    },
+    Job {
+        name: "backfill_advert_fingerprints",
+        interval: Duration::from_secs(60),
+        run: backfill_advert_fingerprints,
+    },
+];
+
+/// Unbans users whose temporary ban is over
+fn lift_expired_bans(state: AppState) -> JobFuture {
+    Box::pin(async move {
+        let db = &state.db;
+        let count = db::lift_expired_bans(db).await?;
+        if count > 0 {
+            info!(count, "Lifted expired bans");
+        }
+        Ok(())
+    })
+}
+
+/// Recounts daily advert views for the seller charts
+fn aggregate_advert_views(state: AppState) -> JobFuture {
+    Box::pin(async move {
+        let first_day = view_stats::day(db::now()) - view_stats::VIEW_RETENTION_DAYS;
+        let db = &state.db;
+        db::aggregate_advert_views(db, first_day).await
+    })
+}
+
+/// Fingerprints adverts stored before near-duplicates were looked for
+fn backfill_advert_fingerprints(state: AppState) -> JobFuture {
+    Box::pin(async move {
+        let db = &state.db;
+        let fingerprints: Vec<AdvertFingerprint> =
+            db::get_adverts_without_fingerprint(db, FINGERPRINT_BATCH)
+                .await?
+                .into_iter()
+                .map(|advert| AdvertFingerprint {
+                    id: advert.id,
+                    fingerprint: content_filter::fingerprint(&advert.title, &advert.content),
+                })
+                .collect();
+        if !fingerprints.is_empty() {
+            db::set_advert_fingerprints(db, &fingerprints).await?;
+            info!(count = fingerprints.len(), "Fingerprinted adverts");
+        }
+        Ok(())
+    })
+}
+
+pub struct Supervisor {
+    tasks: JoinSet<()>,
+    shutdown: watch::Sender<bool>,
+}
+
+impl Supervisor {
+    /// Starts all `JOBS`, each runs right away and then on its interval
+    pub fn start(state: AppState) -> Self {
+        let (shutdown, _) = watch::channel(false);
+        let mut tasks = JoinSet::new();
+        for job in JOBS {
+            tasks.spawn(supervise(job, state.clone(), shutdown.subscribe()));
+        }
+        Supervisor { tasks, shutdown }
+    }
+
+    /// Stops all jobs, waiting for running ones to finish their current run
+    pub async fn shutdown(mut self) {
+        let _ = self.shutdown.send(true);
+        while self.tasks.join_next().await.is_some() {}
+    }
+}
+
+async fn supervise(job: Job, state: AppState, mut shutdown: watch::Receiver<bool>) {
+    let mut interval = tokio::time::interval(job.interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.changed() => break,
+        }
+
+        // Runs are separate tasks, so a panic loses only one run and not the job
+        match tokio::spawn((job.run)(state.clone())).await {
+            Ok(Ok(())) => {}
+            Ok(Err(())) => warn!(job = job.name, "Job run failed"),
+            Err(e) => error!(job = job.name, error = %e, "Job run panicked, restarting"),
+        }
+    }
+    info!(job = job.name, "Job stopped");
+}
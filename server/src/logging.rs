@@ -0,0 +1,81 @@
+//! Structured logging with tracing. Every http request gets a span with its request id,
+//! route and user id, events inside it, sqlx statements too, carry these fields.
+
+use std::time::Duration;
+
+use axum::{
+    body::Body,
+    extract::{MatchedPath, Request},
+    http::Response,
+    middleware::Next,
+};
+use axum_login::AuthSession;
+use tracing::{field, info, info_span, Span};
+use tracing_subscriber::EnvFilter;
+
+use crate::{auth::AuthBackend, config::LogFormat};
+
+const DEFAULT_FILTER: &str = "info";
+const REQUEST_ID_HEADER: &str = "x-request-id";
+const UNMATCHED_ROUTE: &str = "unmatched";
+
+pub fn init(format: LogFormat) {
+    let filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(DEFAULT_FILTER));
+    let subscriber = tracing_subscriber::fmt().with_env_filter(filter);
+    match format {
+        LogFormat::Text => subscriber.init(),
+        LogFormat::Json => subscriber.json().init(),
+    }
+}
+
+/// Span for the whole request, the request id is set by `SetRequestIdLayer` in front of it
+pub fn request_span(request: &Request<Body>) -> Span {
+    let request_id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str())
+        .unwrap_or(UNMATCHED_ROUTE);
+    info_span!(
+        "request",
+        request_id,
+        method = %request.method(),
+        route,
+        user_id = field::Empty,
+    )
+}
+
+/// Logged in user of the request, passed from `record_user` to `log_response`
+#[derive(Clone, Copy)]
+struct RequestUser(i64);
+
+pub fn log_response(response: &Response<Body>, latency: Duration, span: &Span) {
+    if let Some(RequestUser(user_id)) = response.extensions().get() {
+        span.record("user_id", user_id);
+    }
+    info!(
+        status = response.status().as_u16(),
+        latency_ms = latency.as_millis() as u64,
+        "Request finished"
+    );
+}
+
+/// Remembers the logged in user for the request log, must run inside the auth layer.
+/// The auth layer has its own span, so the user is passed on in the response extensions.
+pub async fn record_user(
+    auth_session: AuthSession<AuthBackend>,
+    request: Request,
+    next: Next,
+) -> axum::response::Response {
+    let user_id = auth_session.user.as_ref().map(|user| user.id);
+    let mut response = next.run(request).await;
+    if let Some(user_id) = user_id {
+        response.extensions_mut().insert(RequestUser(user_id));
+    }
+    response
+}
@@ -1,59 +1,120 @@
-use std::sync::Arc;
+use std::{net::SocketAddr, sync::Arc};
 
 use axum::{
+    middleware,
     routing::{get, post},
     Router,
 };
 use axum_csrf::{CsrfConfig, CsrfLayer};
 use axum_login::{login_required, permission_required, AuthManagerLayerBuilder};
+use clap::Parser;
 
 use sqlx::{Pool, Sqlite};
-use tokio::sync::RwLock;
+use tokio::signal;
+use tower_http::{
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
+    trace::TraceLayer,
+};
 use tower_sessions::{MemoryStore, SessionManagerLayer};
+use tracing::info;
 
-use crate::auth::AuthBackend;
+use crate::{auth::AuthBackend, config::Config, jobs::Supervisor, metrics::Metrics};
 
 mod auth;
 mod auth_models;
+mod config;
+mod content_filter;
 mod db;
+mod filters;
+mod jobs;
+mod logging;
+mod markdown;
+mod metrics;
 mod models;
+mod pagination;
+mod redirect;
 mod routes;
+mod throttle;
+mod totp;
+mod view_stats;
 
 #[tokio::main]
 async fn main() {
-    env_logger::init();
+    let config = Config::parse();
+    logging::init(config.log_format);
+
+    let state = app_state(config).await;
+    let jobs = Supervisor::start(state.clone());
 
-    let app = router();
+    let app = router(state);
     let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
-    axum::serve(listener, app.await.into_make_service())
-        .await
-        .unwrap();
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(shutdown_signal())
+    .await
+    .unwrap();
+
+    info!("Server stopped, waiting for background jobs");
+    jobs.shutdown().await;
+}
+
+/// Resolves on Ctrl+C or SIGTERM, in-flight requests are drained after that
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        signal::ctrl_c().await.expect("Failed to listen for Ctrl+C");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("Failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+    info!("Shutting down");
 }
 
+pub const DB_FILE: &str = "simple_bulletin.db";
+
 #[derive(Clone)]
 pub struct AppState {
-    db: Arc<RwLock<Pool<Sqlite>>>,
+    /// The pool is shared as is, SQLite in WAL mode handles concurrent readers and writers
+    db: Pool<Sqlite>,
+    config: Arc<Config>,
+    metrics: Arc<Metrics>,
+}
+
+async fn app_state(config: Config) -> AppState {
+    let db = db::create_db(DB_FILE).await.expect("Failed to create db");
+
+    AppState {
+        db,
+        config: Arc::new(config),
+        metrics: Arc::new(Metrics::new().expect("Failed to create metrics")),
+    }
 }
 
-async fn router() -> Router {
+fn router(state: AppState) -> Router {
     let csrf_config = CsrfConfig::default();
 
     let session_store = MemoryStore::default();
     let session_layer = SessionManagerLayer::new(session_store);
 
-    let db = db::create_db("simple_bulletin.db")
-        .await
-        .expect("Failed to create db");
-
-    let db = Arc::new(RwLock::new(db.clone()));
-
-    let state = AppState { db: db.clone() };
-
-    let backend = AuthBackend::new(db);
+    let backend = AuthBackend::new(state.db.clone());
     let auth_layer = AuthManagerLayerBuilder::new(backend, session_layer).build();
 
     Router::new()
         .merge(mod_router())
+        .merge(admin_router())
         .merge(auth_router())
         .merge(user_router())
         .route("/register", post(routes::register))
@@ -61,28 +122,85 @@ async fn router() -> Router {
         .route("/", get(routes::main_board))
         .route("/item/:id", get(routes::item_page))
         .route("/item/:id", post(routes::item_page_edit))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            routes::require_admin_two_factor,
+        ))
+        .layer(middleware::from_fn(logging::record_user))
         .layer(auth_layer)
         .layer(CsrfLayer::new(csrf_config))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            metrics::track_requests,
+        ))
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(
+            TraceLayer::new_for_http()
+                .make_span_with(logging::request_span)
+                .on_response(logging::log_response),
+        )
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
+        // Scrapers and probes don't need sessions and csrf cookies
+        .route("/metrics", get(routes::metrics_page))
+        .route("/healthz", get(routes::healthz))
+        .route("/readyz", get(routes::readyz))
         .with_state(state)
 }
 
 fn mod_router() -> Router<AppState> {
     Router::new()
+        .route("/mod", post(routes::mod_edit))
+        .route_layer(permission_required!(
+            AuthBackend,
+            login_url = "/login",
+            "mod.write"
+        ))
         .route("/mod", get(routes::mod_page))
+        .route_layer(permission_required!(
+            AuthBackend,
+            login_url = "/login",
+            "mod.read"
+        ))
+}
+
+fn admin_router() -> Router<AppState> {
+    Router::new()
+        .route("/admin/groups", post(routes::group_new))
+        .route("/admin/groups/:id", post(routes::group_edit))
+        .route("/admin/users/:id", post(routes::user_edit))
+        .route("/admin/invites", post(routes::invite_edit))
+        .route("/admin/filters", post(routes::filter_edit))
+        .route("/mod/users", post(routes::mod_users_edit))
+        .route_layer(permission_required!(
+            AuthBackend,
+            login_url = "/login",
+            "admin.write"
+        ))
+        .route("/admin/groups", get(routes::groups_page))
+        .route("/admin/groups/:id", get(routes::group_page))
+        .route("/admin/users/:id", get(routes::user_page))
+        .route("/admin/invites", get(routes::invites_page))
+        .route("/admin/filters", get(routes::filters_page))
+        .route("/admin/stats", get(routes::stats_page))
         .route_layer(permission_required!(
             AuthBackend,
             login_url = "/login",
             "admin.read"
         ))
-        .route("/mod", post(routes::mod_edit))
 }
 
 fn auth_router() -> Router<AppState> {
     Router::new()
         .route("/logout", get(routes::logout))
+        .route("/profile/2fa", get(routes::two_factor_page))
+        .route("/profile/2fa/enable", post(routes::two_factor_enable))
+        .route("/profile/2fa/disable", post(routes::two_factor_disable))
+        .route("/item/:id/report", post(routes::item_report))
         .route_layer(login_required!(AuthBackend, login_url = "/login"))
         .route("/login", post(routes::login_with_password))
         .route("/login", get(routes::login_form))
+        .route("/login/2fa", post(routes::login_two_factor))
+        .route("/login/2fa", get(routes::login_two_factor_form))
 }
 
 fn user_router() -> Router<AppState> {
@@ -94,12 +212,18 @@ fn user_router() -> Router<AppState> {
             "user.read"
         ))
         .route("/item/new", get(routes::item_new_form))
+        .route("/item/:id/edit", get(routes::item_edit_form))
+        .route("/item/:id/edit", post(routes::item_edit))
         .route_layer(permission_required!(
             AuthBackend,
             login_url = "/login",
             "user.write"
         ))
         .route("/profile", get(routes::profile))
+        .route("/profile/settings", get(routes::settings_page))
+        .route("/profile/settings", post(routes::settings_edit))
+        .route("/profile/password", post(routes::settings_password))
+        .route("/profile/delete", post(routes::settings_delete))
         .route_layer(permission_required!(
             AuthBackend,
             login_url = "/login",
@@ -0,0 +1,122 @@
+//! Markdown subset for advert content, rendered to sanitized html on the server.
+
+use std::collections::HashSet;
+use std::sync::OnceLock;
+
+use ammonia::Builder;
+use pulldown_cmark::{html, Event, HeadingLevel, Options, Parser, Tag, TagEnd};
+
+/// Tags the markdown subset can produce, everything else is dropped by the sanitizer
+const ALLOWED_TAGS: [&str; 17] = [
+    "p",
+    "br",
+    "hr",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "ul",
+    "ol",
+    "li",
+    "strong",
+    "em",
+    "del",
+    "code",
+    "pre",
+    "blockquote",
+    "a",
+];
+
+fn sanitizer() -> &'static Builder<'static> {
+    static SANITIZER: OnceLock<Builder<'static>> = OnceLock::new();
+    SANITIZER.get_or_init(|| {
+        let mut builder = Builder::empty();
+        builder
+            .tags(HashSet::from(ALLOWED_TAGS))
+            .add_tag_attributes("a", ["href"])
+            .add_tag_attributes("ol", ["start"])
+            .url_schemes(HashSet::from(["http", "https", "mailto"]))
+            .link_rel(Some("nofollow noopener noreferrer ugc"));
+        builder
+    })
+}
+
+/// Renders `content` to html safe to embed as is. Raw html in the source is shown as text,
+/// images are dropped and links get `rel="nofollow"`.
+pub fn render(content: &str) -> String {
+    let parser = Parser::new_ext(content, Options::ENABLE_STRIKETHROUGH).map(|event| match event {
+        Event::Html(html) | Event::InlineHtml(html) => Event::Text(html),
+        // Sellers write line by line, keep their line breaks
+        Event::SoftBreak => Event::HardBreak,
+        // The page already has h1 for the title and h2 for sections
+        Event::Start(Tag::Heading {
+            level,
+            id,
+            classes,
+            attrs,
+        }) => Event::Start(Tag::Heading {
+            level: level.max(HeadingLevel::H3),
+            id,
+            classes,
+            attrs,
+        }),
+        Event::End(TagEnd::Heading(level)) => {
+            Event::End(TagEnd::Heading(level.max(HeadingLevel::H3)))
+        }
+        event => event,
+    });
+    let mut unsafe_html = String::new();
+    html::push_html(&mut unsafe_html, parser);
+    sanitizer().clean(&unsafe_html).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::render;
+
+    #[test]
+    fn raw_html_is_shown_as_text() {
+        let html = render("<script>alert(1)</script>\n\nHi <b onclick=\"x()\">there</b>");
+        assert!(!html.contains("<script"), "{}", html);
+        assert!(!html.contains("<b"), "{}", html);
+        assert!(html.contains("&lt;script&gt;"), "{}", html);
+    }
+
+    #[test]
+    fn dangerous_link_schemes_are_dropped() {
+        for source in [
+            "[click](javascript:alert(1))",
+            "[click](JavaScript:alert(1))",
+            "[click](data:text/html;base64,PHNjcmlwdD4=)",
+            "<javascript:alert(1)>",
+        ] {
+            // The link text may stay, the href must not
+            let html = render(source);
+            assert!(!html.contains("href"), "{}", html);
+        }
+    }
+
+    #[test]
+    fn images_are_removed() {
+        let html = render("![cat](https://example.com/cat.png)");
+        assert!(!html.contains("<img"), "{}", html);
+        assert!(!html.contains("cat.png"), "{}", html);
+    }
+
+    #[test]
+    fn links_are_nofollow_noopener() {
+        let html = render("[shop](https://example.com/)");
+        assert!(html.contains(r#"href="https://example.com/""#), "{}", html);
+        assert!(
+            html.contains(r#"rel="nofollow noopener noreferrer ugc""#),
+            "{}",
+            html
+        );
+    }
+
+    #[test]
+    fn headings_start_at_h3() {
+        assert_eq!(render("# Title"), "<h3>Title</h3>\n");
+        assert_eq!(render("#### Small"), "<h4>Small</h4>\n");
+    }
+}
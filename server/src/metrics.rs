@@ -0,0 +1,143 @@
+//! Prometheus metrics, served on `/metrics` when `--metrics-token` is set.
+
+use std::time::Instant;
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    middleware::Next,
+    response::Response,
+};
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry, TextEncoder,
+};
+use sqlx::{Pool, Sqlite};
+use tracing::error;
+
+use crate::{db, AppState};
+
+pub const LOGIN_SUCCESS: &str = "success";
+pub const LOGIN_FAILURE: &str = "failure";
+/// Refused before checking the password because of too many failures
+pub const LOGIN_THROTTLED: &str = "throttled";
+/// Right password, but the account is pending activation or banned
+pub const LOGIN_REJECTED: &str = "rejected";
+
+/// Requests not matching any route share one label, so scanners can't blow up label cardinality
+const UNMATCHED_ROUTE: &str = "unmatched";
+
+pub struct Metrics {
+    registry: Registry,
+    http_requests: IntCounterVec,
+    http_request_duration: HistogramVec,
+    logins: IntCounterVec,
+    db_connections: IntGauge,
+    db_idle_connections: IntGauge,
+    moderation_queue: IntGauge,
+    open_reports: IntGauge,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self, prometheus::Error> {
+        let metrics = Metrics {
+            registry: Registry::new_custom(Some("simple_bulletin".to_string()), None)?,
+            http_requests: IntCounterVec::new(
+                Opts::new("http_requests_total", "HTTP requests by route and status"),
+                &["method", "route", "status"],
+            )?,
+            http_request_duration: HistogramVec::new(
+                HistogramOpts::new(
+                    "http_request_duration_seconds",
+                    "HTTP request latency by route",
+                ),
+                &["method", "route"],
+            )?,
+            logins: IntCounterVec::new(
+                Opts::new("login_attempts_total", "Login attempts by result"),
+                &["result"],
+            )?,
+            db_connections: IntGauge::new("db_pool_connections", "Open SQLite connections")?,
+            db_idle_connections: IntGauge::new(
+                "db_pool_idle_connections",
+                "Idle SQLite connections",
+            )?,
+            moderation_queue: IntGauge::new(
+                "moderation_queue_adverts",
+                "Adverts held by the content filter or waiting for the first review",
+            )?,
+            open_reports: IntGauge::new("reported_adverts", "Adverts with open reports")?,
+        };
+
+        metrics
+            .registry
+            .register(Box::new(metrics.http_requests.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.http_request_duration.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.logins.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.db_connections.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.db_idle_connections.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.moderation_queue.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.open_reports.clone()))?;
+        Ok(metrics)
+    }
+
+    pub fn login(&self, result: &str) {
+        self.logins.with_label_values(&[result]).inc();
+    }
+
+    /// Updates gauges sampled at scrape time and renders all metrics in the text format
+    pub async fn render(&self, db: &Pool<Sqlite>) -> Result<(String, Vec<u8>), ()> {
+        self.db_connections.set(db.size() as i64);
+        self.db_idle_connections.set(db.num_idle() as i64);
+        let stats = db::get_advert_stats(db).await?;
+        self.moderation_queue.set(stats.queued);
+        self.open_reports.set(stats.reported);
+
+        let encoder = TextEncoder::new();
+        let mut buffer = vec![];
+        encoder
+            .encode(&self.registry.gather(), &mut buffer)
+            .map_err(|e| {
+                error!(error = %e, "Failed to encode metrics");
+            })?;
+        Ok((encoder.format_type().to_string(), buffer))
+    }
+}
+
+/// Middleware counting requests and their latency per route pattern
+pub async fn track_requests(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let method = request.method().to_string();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| UNMATCHED_ROUTE.to_string());
+
+    let started = Instant::now();
+    let response = next.run(request).await;
+
+    let metrics = &state.metrics;
+    metrics
+        .http_request_duration
+        .with_label_values(&[&method, &route])
+        .observe(started.elapsed().as_secs_f64());
+    metrics
+        .http_requests
+        .with_label_values(&[&method, &route, response.status().as_str()])
+        .inc();
+    response
+}
@@ -1,10 +1,270 @@
+use std::fmt::Display;
+
 use serde::{Deserialize, Serialize};
 use sqlx::FromRow;
 
 #[derive(Deserialize, Serialize, Debug, Clone, FromRow)]
 pub struct Advert {
     pub id: i64,
+    /// Author, NULL only for adverts whose author was lost before ownership moved to adverts
+    pub user_id: Option<i64>,
     pub title: String,
     pub content: String,
     pub published: bool,
+    /// Content filter rule which held the advert for review
+    pub moderation_rule: Option<String>,
+    pub fingerprint: Option<i64>,
+    pub views: i64,
+}
+
+/// Public info about the author shown on the advert page
+#[derive(Deserialize, Serialize, Debug, Clone, FromRow)]
+pub struct Seller {
+    pub id: i64,
+    pub username: String,
+    pub display_name: Option<String>,
+    /// 0 for accounts created before registration dates were stored
+    pub created_at: i64,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default, FromRow)]
+pub struct UserSettings {
+    pub display_name: Option<String>,
+    pub email: Option<String>,
+    pub contact_phone: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, FromRow)]
+pub struct LoginLock {
+    pub id: i64,
+    pub kind: String,
+    pub value: String,
+    pub failures: i64,
+    pub locked_until: i64,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, FromRow)]
+pub struct Group {
+    pub id: i64,
+    pub name: String,
+    pub members_count: i64,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, FromRow)]
+pub struct GroupPermission {
+    pub id: i64,
+    pub name: String,
+    pub granted: bool,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, FromRow)]
+pub struct GroupMember {
+    pub id: i64,
+    pub username: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, FromRow)]
+pub struct UserPermission {
+    pub id: i64,
+    pub name: String,
+    pub from_groups: bool,
+    /// Per-user override, `Some(false)` denies the permission even if a group grants it
+    pub override_granted: Option<bool>,
+}
+
+impl UserPermission {
+    pub fn effective(&self) -> bool {
+        self.override_granted.unwrap_or(self.from_groups)
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, FromRow)]
+pub struct Invite {
+    pub id: i64,
+    pub code: String,
+    pub created_by: Option<String>,
+    pub created_at: i64,
+    pub expires_at: Option<i64>,
+    pub used_by: Option<String>,
+    pub used_at: Option<i64>,
+    pub expired: bool,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub enum ReportReason {
+    Scam,
+    Prohibited,
+    Spam,
+    Offensive,
+    Other,
+}
+
+impl ReportReason {
+    pub const ALL: [ReportReason; 5] = [
+        ReportReason::Scam,
+        ReportReason::Prohibited,
+        ReportReason::Spam,
+        ReportReason::Offensive,
+        ReportReason::Other,
+    ];
+
+    pub fn value(&self) -> &'static str {
+        match self {
+            ReportReason::Scam => "scam",
+            ReportReason::Prohibited => "prohibited",
+            ReportReason::Spam => "spam",
+            ReportReason::Offensive => "offensive",
+            ReportReason::Other => "other",
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            ReportReason::Scam => "Scam or fraud",
+            ReportReason::Prohibited => "Prohibited goods or services",
+            ReportReason::Spam => "Spam or duplicate",
+            ReportReason::Offensive => "Offensive content",
+            ReportReason::Other => "Other",
+        }
+    }
+}
+
+/// Advert with pending reports, for the moderation queue
+#[derive(Deserialize, Serialize, Debug, Clone, FromRow)]
+pub struct ReportedAdvert {
+    pub id: i64,
+    pub title: String,
+    pub published: bool,
+    pub reports_count: i64,
+    pub reasons: String,
+    pub details: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub enum FilterRuleKind {
+    /// Whole word, case insensitive
+    Word,
+    Regex,
+    Link,
+    Phone,
+    /// Same normalized text as another advert
+    Duplicate,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub enum FilterRuleAction {
+    Hold,
+    Reject,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, FromRow)]
+pub struct FilterRule {
+    pub id: i64,
+    pub kind: FilterRuleKind,
+    pub pattern: String,
+    pub action: FilterRuleAction,
+}
+
+impl FilterRuleKind {
+    pub const ALL: [FilterRuleKind; 5] = [
+        FilterRuleKind::Word,
+        FilterRuleKind::Regex,
+        FilterRuleKind::Link,
+        FilterRuleKind::Phone,
+        FilterRuleKind::Duplicate,
+    ];
+
+    pub fn value(&self) -> &'static str {
+        match self {
+            FilterRuleKind::Word => "word",
+            FilterRuleKind::Regex => "regex",
+            FilterRuleKind::Link => "link",
+            FilterRuleKind::Phone => "phone",
+            FilterRuleKind::Duplicate => "duplicate",
+        }
+    }
+}
+
+impl FilterRuleAction {
+    pub fn value(&self) -> &'static str {
+        match self {
+            FilterRuleAction::Hold => "hold",
+            FilterRuleAction::Reject => "reject",
+        }
+    }
+}
+
+impl Display for FilterRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.kind {
+            FilterRuleKind::Word | FilterRuleKind::Regex => {
+                write!(f, "#{} {} \"{}\"", self.id, self.kind.value(), self.pattern)
+            }
+            _ => write!(f, "#{} {}", self.id, self.kind.value()),
+        }
+    }
+}
+
+/// Content filter outcome stored with a new or edited advert
+#[derive(Debug, Clone)]
+pub struct AdvertModeration {
+    pub published: bool,
+    pub moderation_rule: Option<String>,
+    pub content_hash: String,
+    pub fingerprint: i64,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, FromRow)]
+pub struct AdvertFingerprint {
+    pub id: i64,
+    pub fingerprint: i64,
+}
+
+/// Limits on new adverts per user, 0 disables a limit
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AdvertQuota {
+    /// Published adverts and ones awaiting review
+    pub max_published: i64,
+    /// New adverts are published without review, only the ones held by a rule await it
+    pub auto_approve: bool,
+    pub max_per_hour: i64,
+    pub max_per_day: i64,
+}
+
+#[derive(Debug, Clone, Default, FromRow)]
+pub struct UserStats {
+    pub total: i64,
+    pub active: i64,
+    /// Registered but not activated yet
+    pub pending: i64,
+    pub banned: i64,
+}
+
+#[derive(Debug, Clone, Default, FromRow)]
+pub struct AdvertStats {
+    pub total: i64,
+    pub published: i64,
+    /// Held by the content filter
+    pub held: i64,
+    /// Unpublished by a moderator, reports or the author
+    pub unpublished: i64,
+    /// Not published and never looked at by a moderator
+    pub awaiting_review: i64,
+    /// Held or awaiting the first review
+    pub queued: i64,
+    pub reported: i64,
+}
+
+/// Time from advert creation to the first moderator decision
+#[derive(Debug, Clone, Default, FromRow)]
+pub struct ModerationLatency {
+    pub reviewed: i64,
+    pub average_secs: i64,
+    pub max_secs: i64,
 }
@@ -1,9 +1,13 @@
 use clap::Parser;
-use password_auth::generate_hash;
 
+#[allow(dead_code)]
 mod auth_models;
+#[allow(dead_code)]
 mod db;
+#[allow(dead_code)]
 mod models;
+#[allow(dead_code)]
+mod pagination;
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
@@ -14,6 +18,7 @@ struct Cli {
 
 #[tokio::main]
 async fn main() {
+    tracing_subscriber::fmt::init();
     let cli = Cli::parse();
 
     let admin_username = cli.username;
@@ -28,24 +33,7 @@ async fn create_new_admin(username: &str, password: &str) {
         .await
         .expect("Failed to create db");
 
-    sqlx::query("INSERT INTO users(username, password_hash, active) VALUES(?, ?, ?)")
-        .bind(username)
-        .bind(generate_hash(password))
-        .bind(true)
-        .execute(&db)
+    db::create_new_user(&db, username, password, &["users", "admins"], true, None)
         .await
         .unwrap();
-    sqlx::query(
-        r#"INSERT INTO
-                 users_groups(user_id, group_id)
-                 VALUES(
-                    (SELECT id FROM users WHERE username = ?),
-                    (SELECT id FROM groups WHERE name = ?)
-                )"#,
-    )
-    .bind(username)
-    .bind("admins")
-    .execute(&db)
-    .await
-    .unwrap();
 }
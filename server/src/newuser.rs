@@ -1,12 +1,20 @@
+#[allow(dead_code)]
 mod auth_models;
+#[allow(dead_code)]
 mod db;
+#[allow(dead_code)]
 mod models;
+#[allow(dead_code)]
+mod pagination;
 
 #[tokio::main]
 async fn main() {
+    tracing_subscriber::fmt::init();
     println!("Create new user");
     let database = db::create_db("simple_bulletin.db")
         .await
         .expect("Can't open database");
-    db::create_new_user(&database, "user", "123").await.unwrap();
+    db::create_new_user(&database, "user", "123", &["users"], false, None)
+        .await
+        .unwrap();
 }
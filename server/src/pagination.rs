@@ -0,0 +1,241 @@
+//! Keyset pagination for listings ordered by id, newest first. Pages are addressed by the
+//! id of a row next to them instead of an offset, so deep pages cost the same as the first
+//! one and rows added meanwhile don't shift the pages.
+
+use std::fmt;
+
+use crate::{auth_models::User, models::Advert};
+
+/// Query value of the last page, the oldest rows
+const LAST_PAGE: &str = "last";
+
+/// Position of a page relative to a row of the neighbouring page
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Cursor {
+    /// Rows older than the id, the next page
+    After(i64),
+    /// Rows newer than the id, the previous page
+    Before(i64),
+}
+
+impl Cursor {
+    /// Parses a page query value, anything unknown means the first page
+    pub fn parse(value: Option<&str>) -> Option<Cursor> {
+        let value = value?;
+        if value == LAST_PAGE {
+            return Some(Cursor::Before(0));
+        }
+        let (direction, id) = value.split_once('-')?;
+        let id = id.parse().ok().filter(|id| *id >= 0)?;
+        match direction {
+            "after" => Some(Cursor::After(id)),
+            "before" => Some(Cursor::Before(id)),
+            _ => None,
+        }
+    }
+
+    /// Query value for the page, empty for the first one
+    pub fn param(cursor: Option<Cursor>) -> String {
+        cursor.map(|cursor| cursor.to_string()).unwrap_or_default()
+    }
+}
+
+impl fmt::Display for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Cursor::After(id) => write!(f, "after-{}", id),
+            Cursor::Before(id) => write!(f, "before-{}", id),
+        }
+    }
+}
+
+/// Rows which can be paged through, `key` is the unique id they are ordered by
+pub trait Keyed {
+    fn key(&self) -> i64;
+}
+
+impl Keyed for Advert {
+    fn key(&self) -> i64 {
+        self.id
+    }
+}
+
+impl Keyed for User {
+    fn key(&self) -> i64 {
+        self.id
+    }
+}
+
+/// Which page of a listing to load
+#[derive(Debug, Clone, Copy)]
+pub struct PageRequest {
+    cursor: Option<Cursor>,
+    limit: i64,
+}
+
+impl PageRequest {
+    pub fn new(cursor: Option<Cursor>, limit: i64) -> Self {
+        PageRequest { cursor, limit }
+    }
+
+    /// Condition on `id` selecting rows of the page, `key` is bound to it
+    pub fn condition(&self) -> &'static str {
+        match self.cursor {
+            None | Some(Cursor::After(_)) => "id < ?",
+            Some(Cursor::Before(_)) => "id > ?",
+        }
+    }
+
+    /// Order to read rows in, pages before the cursor are read upwards from it
+    pub fn order(&self) -> &'static str {
+        match self.cursor {
+            None | Some(Cursor::After(_)) => "DESC",
+            Some(Cursor::Before(_)) => "ASC",
+        }
+    }
+
+    pub fn key(&self) -> i64 {
+        match self.cursor {
+            None => i64::MAX,
+            Some(Cursor::After(id)) | Some(Cursor::Before(id)) => id,
+        }
+    }
+
+    /// One row more than fits the page, to know whether there is a page beyond it
+    pub fn fetch_limit(&self) -> i64 {
+        self.limit + 1
+    }
+
+    /// Builds the page from rows read with `condition`, `order` and `fetch_limit`
+    pub fn page<T: Keyed>(&self, mut rows: Vec<T>) -> Page<T> {
+        let more = rows.len() as i64 > self.limit;
+        rows.truncate(self.limit.max(0) as usize);
+
+        let (prev, next) = match self.cursor {
+            None => (false, more),
+            Some(Cursor::After(_)) => (true, more),
+            Some(Cursor::Before(id)) => {
+                rows.reverse();
+                // Before(0) is the last page, the others were reached from an older page
+                (more, id > 0)
+            }
+        };
+        let prev = prev.then(|| rows.first().map(|row| Cursor::Before(row.key())));
+        let next = next.then(|| rows.last().map(|row| Cursor::After(row.key())));
+        Page {
+            current: Cursor::param(self.cursor),
+            prev: Cursor::param(prev.flatten()),
+            next: Cursor::param(next.flatten()),
+            items: rows,
+        }
+    }
+}
+
+/// A page of a listing with query values of the neighbouring pages, empty if there is none
+#[derive(Debug)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub current: String,
+    pub prev: String,
+    pub next: String,
+}
+
+impl<T> Page<T> {
+    pub fn map<U>(self, f: impl FnMut(T) -> U) -> Page<U> {
+        Page {
+            items: self.items.into_iter().map(f).collect(),
+            current: self.current,
+            prev: self.prev,
+            next: self.next,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Row(i64);
+
+    impl Keyed for Row {
+        fn key(&self) -> i64 {
+            self.0
+        }
+    }
+
+    /// Reads rows `1..=count` the way the SQL built from the request would
+    fn load(count: i64, page: &str, limit: i64) -> Page<i64> {
+        let request = PageRequest::new(Cursor::parse(Some(page)), limit);
+        let mut ids: Vec<i64> = match request.condition() {
+            "id < ?" => (1..=count).filter(|id| *id < request.key()).collect(),
+            _ => (1..=count).filter(|id| *id > request.key()).collect(),
+        };
+        if request.order() == "DESC" {
+            ids.reverse();
+        }
+        ids.truncate(request.fetch_limit() as usize);
+        request
+            .page(ids.into_iter().map(Row).collect())
+            .map(|row| row.0)
+    }
+
+    fn assert_page(page: &Page<i64>, items: &[i64], prev: &str, next: &str) {
+        assert_eq!(page.items, items);
+        assert_eq!(page.prev, prev);
+        assert_eq!(page.next, next);
+    }
+
+    #[test]
+    fn parses_cursors() {
+        assert_eq!(Cursor::parse(None), None);
+        assert_eq!(Cursor::parse(Some("")), None);
+        assert_eq!(Cursor::parse(Some("after-5")), Some(Cursor::After(5)));
+        assert_eq!(Cursor::parse(Some("before-5")), Some(Cursor::Before(5)));
+        assert_eq!(Cursor::parse(Some("last")), Some(Cursor::Before(0)));
+        assert_eq!(Cursor::parse(Some("after--5")), None);
+        assert_eq!(Cursor::parse(Some("after-x")), None);
+        assert_eq!(Cursor::parse(Some("around-5")), None);
+        assert_eq!(Cursor::param(Some(Cursor::After(5))), "after-5");
+        assert_eq!(Cursor::param(None), "");
+    }
+
+    #[test]
+    fn empty_listing_has_no_pages() {
+        assert_page(&load(0, "", 3), &[], "", "");
+        assert_page(&load(0, "last", 3), &[], "", "");
+    }
+
+    #[test]
+    fn single_page_has_no_neighbours() {
+        assert_page(&load(2, "", 3), &[2, 1], "", "");
+        assert_page(&load(3, "", 3), &[3, 2, 1], "", "");
+    }
+
+    #[test]
+    fn pages_forward_over_exact_multiple() {
+        let first = load(6, "", 3);
+        assert_page(&first, &[6, 5, 4], "", "after-4");
+        let second = load(6, &first.next, 3);
+        assert_eq!(second.current, "after-4");
+        // The extra row is not there, so the second page is the last one
+        assert_page(&second, &[3, 2, 1], "before-3", "");
+    }
+
+    #[test]
+    fn pages_backwards() {
+        let last = load(7, "last", 3);
+        assert_page(&last, &[3, 2, 1], "before-3", "");
+        let middle = load(7, &last.prev, 3);
+        assert_page(&middle, &[6, 5, 4], "before-6", "after-4");
+        let first = load(7, &middle.prev, 3);
+        // Only one row is left above, the first page is short when paging back to it
+        assert_page(&first, &[7], "", "after-7");
+        assert_page(&load(7, "before-3", 3), &[6, 5, 4], "before-6", "after-4");
+    }
+
+    #[test]
+    fn last_page_of_exact_multiple() {
+        assert_page(&load(6, "last", 3), &[3, 2, 1], "before-3", "");
+        assert_page(&load(6, "before-3", 3), &[6, 5, 4], "", "after-4");
+    }
+}
@@ -0,0 +1,119 @@
+//! Post-action redirects to user supplied urls, limited to paths on this site.
+
+use axum::response::Redirect;
+
+/// How many times percent-encoding is unwrapped before the url is considered suspicious
+const MAX_DECODE_ROUNDS: usize = 3;
+
+/// Redirects to `next` if it is a safe relative path, otherwise to `fallback`
+pub fn safe_redirect(next: Option<&str>, fallback: &str) -> Redirect {
+    match next {
+        Some(next) if is_safe_redirect(next) => Redirect::to(next),
+        _ => Redirect::to(fallback),
+    }
+}
+
+/// Accepts only same-origin absolute paths like `/item/1?page=2`.
+/// Scheme-relative urls (`//host`), backslashes which browsers treat as slashes,
+/// control characters and the same prefixes hidden behind percent-encoding are rejected.
+pub fn is_safe_redirect(url: &str) -> bool {
+    if !has_safe_prefix(url)
+        || url
+            .chars()
+            .any(|c| c == '\\' || c.is_control() || c.is_whitespace())
+    {
+        return false;
+    }
+    let mut current = url.to_string();
+    for _ in 0..MAX_DECODE_ROUNDS {
+        match percent_decode(&current) {
+            Some(decoded) if decoded == current => return true,
+            Some(decoded) if has_safe_prefix(&decoded) => current = decoded,
+            _ => return false,
+        }
+    }
+    false
+}
+
+fn has_safe_prefix(path: &str) -> bool {
+    let mut chars = path.chars();
+    if chars.next() != Some('/') {
+        return false;
+    }
+    match chars.next() {
+        Some(c) => !(c == '/' || c == '\\' || c.is_control() || c.is_whitespace()),
+        None => true,
+    }
+}
+
+/// Decodes `%XX` sequences, returns `None` for malformed input
+fn percent_decode(input: &str) -> Option<String> {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = bytes.get(i + 1..i + 3)?;
+            let hex = std::str::from_utf8(hex).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::is_safe_redirect;
+
+    #[test]
+    fn accepts_local_paths() {
+        assert!(is_safe_redirect("/"));
+        assert!(is_safe_redirect("/item/1"));
+        assert!(is_safe_redirect("/item/1?x=y"));
+        assert!(is_safe_redirect("/mod?advert_page=after-16&user_page="));
+        assert!(is_safe_redirect("/search?q=%D0%B4%D0%BE%D0%BC"));
+    }
+
+    #[test]
+    fn rejects_other_hosts() {
+        assert!(!is_safe_redirect("//evil.com"));
+        assert!(!is_safe_redirect("//evil.com/item/1"));
+        assert!(!is_safe_redirect("http://evil.com"));
+        assert!(!is_safe_redirect("https://evil.com/"));
+    }
+
+    #[test]
+    fn rejects_backslashes() {
+        assert!(!is_safe_redirect("/\\evil.com"));
+        assert!(!is_safe_redirect("\\\\evil.com"));
+        assert!(!is_safe_redirect("/item\\1"));
+    }
+
+    #[test]
+    fn rejects_encoded_prefixes() {
+        assert!(!is_safe_redirect("/%2F%2Fevil.com"));
+        assert!(!is_safe_redirect("/%2fevil.com"));
+        assert!(!is_safe_redirect("/%5Cevil.com"));
+        assert!(!is_safe_redirect("/%252F%252Fevil.com"));
+        assert!(!is_safe_redirect("/%zz"));
+    }
+
+    #[test]
+    fn rejects_schemes() {
+        assert!(!is_safe_redirect("javascript:alert(1)"));
+        assert!(!is_safe_redirect("%6Aavascript:alert(1)"));
+        assert!(!is_safe_redirect("data:text/html,hi"));
+    }
+
+    #[test]
+    fn rejects_empty_and_whitespace() {
+        assert!(!is_safe_redirect(""));
+        assert!(!is_safe_redirect(" /item/1"));
+        assert!(!is_safe_redirect("/\t/evil.com"));
+        assert!(!is_safe_redirect("/item/1\r\nSet-Cookie: x=y"));
+    }
+}
@@ -0,0 +1,665 @@
+use askama::Template;
+use askama_axum::IntoResponse;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{Html, Redirect, Response},
+    Form,
+};
+use axum_csrf::CsrfToken;
+use axum_login::AuthSession;
+use data_encoding::BASE32_NOPAD;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::Deserialize;
+use tracing::error;
+
+use crate::{
+    auth::AuthBackend,
+    auth_models::User,
+    content_filter, db, filters,
+    models::{
+        AdvertStats, FilterRule, FilterRuleAction, FilterRuleKind, Group, GroupMember,
+        GroupPermission, Invite, ModerationLatency, UserPermission, UserStats,
+    },
+    view_stats, AppState,
+};
+
+const GROUP_NAME_MAX_LEN: usize = 64;
+
+const GRANT_PERMISSION_ACTION: &str = "gp";
+const REVOKE_PERMISSION_ACTION: &str = "rp";
+const ADD_MEMBER_ACTION: &str = "am";
+const REMOVE_MEMBER_ACTION: &str = "rm";
+
+const DENY_PERMISSION_ACTION: &str = "dp";
+const CLEAR_PERMISSION_ACTION: &str = "cp";
+const BAN_USER_ACTION: &str = "bu";
+const UNBAN_USER_ACTION: &str = "ub";
+
+const BAN_REASON_MAX_LEN: usize = 256;
+
+const CREATE_INVITE_ACTION: &str = "ci";
+const DELETE_INVITE_ACTION: &str = "di";
+
+const INVITE_CODE_LEN: usize = 10;
+
+const CREATE_RULE_ACTION: &str = "cr";
+const DELETE_RULE_ACTION: &str = "dr";
+
+const RULE_PATTERN_MAX_LEN: usize = 256;
+
+#[derive(Template)]
+#[template(path = "admin_groups.html")]
+struct GroupsPageTemplate<'a> {
+    csrf_token: &'a str,
+    groups: Vec<Group>,
+    error: Option<&'a str>,
+    logged_in: bool,
+}
+
+#[derive(Template)]
+#[template(path = "admin_group.html")]
+struct GroupPageTemplate<'a> {
+    csrf_token: &'a str,
+    group: Group,
+    permissions: Vec<GroupPermission>,
+    members: Vec<GroupMember>,
+    error: Option<&'a str>,
+    logged_in: bool,
+}
+
+#[derive(Template)]
+#[template(path = "admin_user.html")]
+struct UserPageTemplate<'a> {
+    csrf_token: &'a str,
+    user: User,
+    permissions: Vec<UserPermission>,
+    error: Option<&'a str>,
+    logged_in: bool,
+}
+
+#[derive(Template)]
+#[template(path = "admin_invites.html")]
+struct InvitesPageTemplate<'a> {
+    csrf_token: &'a str,
+    invites: Vec<Invite>,
+    registration_mode: String,
+    error: Option<&'a str>,
+    logged_in: bool,
+}
+
+#[derive(Template)]
+#[template(path = "admin_filters.html")]
+struct FiltersPageTemplate<'a> {
+    csrf_token: &'a str,
+    rules: Vec<FilterRule>,
+    kinds: [FilterRuleKind; 5],
+    auto_approve: bool,
+    error: Option<&'a str>,
+    logged_in: bool,
+}
+
+#[derive(Deserialize)]
+pub struct NewGroupForm {
+    csrf_token: String,
+    name: String,
+}
+
+#[derive(Deserialize)]
+pub struct GroupEditForm {
+    csrf_token: String,
+    action: String,
+    id: Option<i64>,
+    username: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct UserEditForm {
+    csrf_token: String,
+    action: String,
+    id: Option<i64>,
+    /// Ban duration, 0 bans forever
+    ban_hours: Option<i64>,
+    reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct FilterEditForm {
+    csrf_token: String,
+    action: String,
+    id: Option<i64>,
+    kind: Option<FilterRuleKind>,
+    pattern: Option<String>,
+    rule_action: Option<FilterRuleAction>,
+}
+
+#[derive(Deserialize)]
+pub struct InviteEditForm {
+    csrf_token: String,
+    action: String,
+    id: Option<i64>,
+    /// Invite lifetime, 0 never expires
+    expires_days: Option<i64>,
+}
+
+async fn render_groups_page(state: &AppState, token: CsrfToken, error: Option<&str>) -> Response {
+    let csrf_token = if let Ok(csrf_token) = token.authenticity_token() {
+        csrf_token
+    } else {
+        return "Failed to get csrf token".into_response();
+    };
+
+    let db = &state.db;
+    let groups = if let Ok(groups) = db::get_groups(db).await {
+        groups
+    } else {
+        return "Failed to load groups".into_response();
+    };
+
+    let template = GroupsPageTemplate {
+        csrf_token: &csrf_token,
+        groups,
+        error,
+        logged_in: true,
+    };
+    let reply_html = template.render().unwrap();
+    let status = if error.is_some() {
+        StatusCode::BAD_REQUEST
+    } else {
+        StatusCode::OK
+    };
+    (status, token, Html(reply_html)).into_response()
+}
+
+async fn render_group_page(
+    state: &AppState,
+    token: CsrfToken,
+    group_id: i64,
+    error: Option<&str>,
+) -> Response {
+    let csrf_token = if let Ok(csrf_token) = token.authenticity_token() {
+        csrf_token
+    } else {
+        return "Failed to get csrf token".into_response();
+    };
+
+    let db = &state.db;
+    let group = match db::get_group(db, group_id).await {
+        Ok(Some(group)) => group,
+        Ok(None) => return (StatusCode::NOT_FOUND, "Not found").into_response(),
+        Err(_) => return "Failed to load group".into_response(),
+    };
+    let (permissions, members) = if let (Ok(permissions), Ok(members)) = (
+        db::get_group_permissions(db, group_id).await,
+        db::get_group_members(db, group_id).await,
+    ) {
+        (permissions, members)
+    } else {
+        return "Failed to load group".into_response();
+    };
+
+    let template = GroupPageTemplate {
+        csrf_token: &csrf_token,
+        group,
+        permissions,
+        members,
+        error,
+        logged_in: true,
+    };
+    let reply_html = template.render().unwrap();
+    let status = if error.is_some() {
+        StatusCode::BAD_REQUEST
+    } else {
+        StatusCode::OK
+    };
+    (status, token, Html(reply_html)).into_response()
+}
+
+pub async fn groups_page(State(state): State<AppState>, token: CsrfToken) -> impl IntoResponse {
+    render_groups_page(&state, token, None).await
+}
+
+pub async fn group_new(
+    State(state): State<AppState>,
+    token: CsrfToken,
+    Form(form): Form<NewGroupForm>,
+) -> impl IntoResponse {
+    if token.verify(&form.csrf_token).is_err() {
+        return "Failed to verify csrf".into_response();
+    }
+
+    let name = form.name.trim();
+    let valid = !name.is_empty()
+        && name.len() <= GROUP_NAME_MAX_LEN
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_-.".contains(c));
+    if !valid {
+        return render_groups_page(
+            &state,
+            token,
+            Some("Group name may contain only latin letters, digits, '_', '-' and '.'"),
+        )
+        .await;
+    }
+
+    match db::create_group(&state.db, name).await {
+        Ok(group_id) => Redirect::to(&format!("/admin/groups/{}", group_id)).into_response(),
+        Err(_) => {
+            render_groups_page(&state, token, Some("Group with this name already exists")).await
+        }
+    }
+}
+
+pub async fn group_page(
+    State(state): State<AppState>,
+    token: CsrfToken,
+    Path(group_id): Path<i64>,
+) -> impl IntoResponse {
+    render_group_page(&state, token, group_id, None).await
+}
+
+pub async fn group_edit(
+    State(state): State<AppState>,
+    token: CsrfToken,
+    auth_session: AuthSession<AuthBackend>,
+    Path(group_id): Path<i64>,
+    Form(form): Form<GroupEditForm>,
+) -> impl IntoResponse {
+    if token.verify(&form.csrf_token).is_err() {
+        return "Failed to verify csrf".into_response();
+    }
+
+    let user = if let Some(user) = auth_session.user {
+        user
+    } else {
+        return "User not found".into_response();
+    };
+
+    let db = &state.db;
+    let members = if let Ok(members) = db::get_group_members(db, group_id).await {
+        members
+    } else {
+        return "Failed to load group".into_response();
+    };
+    let own_group = members.iter().any(|member| member.id == user.id);
+
+    // Admins can't take away their own access, someone else has to do it
+    let result = match (form.action.as_str(), form.id, form.username.as_deref()) {
+        (GRANT_PERMISSION_ACTION, Some(permission_id), _) => {
+            db::grant_group_permission(db, group_id, permission_id)
+                .await
+                .map_err(|_| "Failed to grant permission")
+        }
+        (REVOKE_PERMISSION_ACTION, Some(permission_id), _) => {
+            let permissions = db::get_group_permissions(db, group_id)
+                .await
+                .unwrap_or_default();
+            let is_admin_permission = permissions
+                .iter()
+                .any(|p| p.id == permission_id && p.name.starts_with("admin."));
+            if own_group && is_admin_permission {
+                Err("You can't revoke admin permissions from your own group")
+            } else {
+                db::revoke_group_permission(db, group_id, permission_id)
+                    .await
+                    .map_err(|_| "Failed to revoke permission")
+            }
+        }
+        (ADD_MEMBER_ACTION, _, Some(username)) => {
+            match db::add_user_to_group(db, group_id, username.trim()).await {
+                Ok(true) => Ok(()),
+                Ok(false) => Err("User not found"),
+                Err(_) => Err("Failed to add user to group"),
+            }
+        }
+        (REMOVE_MEMBER_ACTION, Some(user_id), _) => {
+            if user_id == user.id {
+                Err("You can't remove yourself from a group")
+            } else {
+                db::remove_user_from_group(db, group_id, user_id)
+                    .await
+                    .map_err(|_| "Failed to remove user from group")
+            }
+        }
+        _ => Err("Unknown action"),
+    };
+
+    match result {
+        Ok(()) => Redirect::to(&format!("/admin/groups/{}", group_id)).into_response(),
+        Err(error) => render_group_page(&state, token, group_id, Some(error)).await,
+    }
+}
+
+async fn render_user_page(
+    state: &AppState,
+    token: CsrfToken,
+    user_id: i64,
+    error: Option<&str>,
+) -> Response {
+    let csrf_token = if let Ok(csrf_token) = token.authenticity_token() {
+        csrf_token
+    } else {
+        return "Failed to get csrf token".into_response();
+    };
+
+    let db = &state.db;
+    let user = match db::get_user(db, user_id).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return (StatusCode::NOT_FOUND, "Not found").into_response(),
+        Err(_) => return "Failed to load user".into_response(),
+    };
+    let permissions = if let Ok(permissions) = db::get_user_permissions(db, user_id).await {
+        permissions
+    } else {
+        return "Failed to load user".into_response();
+    };
+
+    let template = UserPageTemplate {
+        csrf_token: &csrf_token,
+        user,
+        permissions,
+        error,
+        logged_in: true,
+    };
+    let reply_html = template.render().unwrap();
+    let status = if error.is_some() {
+        StatusCode::BAD_REQUEST
+    } else {
+        StatusCode::OK
+    };
+    (status, token, Html(reply_html)).into_response()
+}
+
+pub async fn user_page(
+    State(state): State<AppState>,
+    token: CsrfToken,
+    Path(user_id): Path<i64>,
+) -> impl IntoResponse {
+    render_user_page(&state, token, user_id, None).await
+}
+
+pub async fn user_edit(
+    State(state): State<AppState>,
+    token: CsrfToken,
+    auth_session: AuthSession<AuthBackend>,
+    Path(user_id): Path<i64>,
+    Form(form): Form<UserEditForm>,
+) -> impl IntoResponse {
+    if token.verify(&form.csrf_token).is_err() {
+        return "Failed to verify csrf".into_response();
+    }
+
+    let admin = if let Some(admin) = auth_session.user {
+        admin
+    } else {
+        return "User not found".into_response();
+    };
+    let is_self = admin.id == user_id;
+
+    let db = &state.db;
+    let result = match (form.action.as_str(), form.id) {
+        (GRANT_PERMISSION_ACTION, Some(permission_id)) => {
+            db::set_user_permission(db, user_id, permission_id, Some(true))
+                .await
+                .map_err(|_| "Failed to grant permission")
+        }
+        (DENY_PERMISSION_ACTION, Some(permission_id)) => {
+            let permissions = db::get_user_permissions(db, user_id)
+                .await
+                .unwrap_or_default();
+            let is_admin_permission = permissions
+                .iter()
+                .any(|p| p.id == permission_id && p.name.starts_with("admin."));
+            if is_self && is_admin_permission {
+                Err("You can't deny admin permissions to yourself")
+            } else {
+                db::set_user_permission(db, user_id, permission_id, Some(false))
+                    .await
+                    .map_err(|_| "Failed to deny permission")
+            }
+        }
+        (CLEAR_PERMISSION_ACTION, Some(permission_id)) => {
+            db::set_user_permission(db, user_id, permission_id, None)
+                .await
+                .map_err(|_| "Failed to clear permission")
+        }
+        (BAN_USER_ACTION, _) if is_self => Err("You can't ban yourself"),
+        (BAN_USER_ACTION, _) => {
+            let reason = form
+                .reason
+                .as_deref()
+                .map(str::trim)
+                .filter(|reason| !reason.is_empty());
+            let hours = form.ban_hours.unwrap_or(0);
+            // Durations past the timestamp range are rejected instead of wrapping around
+            let until = hours
+                .checked_mul(60 * 60)
+                .and_then(|secs| db::now().checked_add(secs));
+            if hours < 0 || until.is_none() {
+                Err("Ban duration is not valid")
+            } else if reason.is_some_and(|reason| reason.chars().count() > BAN_REASON_MAX_LEN) {
+                Err("Ban reason is too long")
+            } else {
+                let until = until.filter(|_| hours > 0);
+                db::ban_user(db, user_id, until, reason)
+                    .await
+                    .map_err(|_| "Failed to ban user")
+            }
+        }
+        (UNBAN_USER_ACTION, _) => db::unban_user(db, user_id)
+            .await
+            .map(|_| ())
+            .map_err(|_| "Failed to unban user"),
+        _ => Err("Unknown action"),
+    };
+
+    match result {
+        Ok(()) => Redirect::to(&format!("/admin/users/{}", user_id)).into_response(),
+        Err(error) => render_user_page(&state, token, user_id, Some(error)).await,
+    }
+}
+
+fn generate_invite_code() -> Result<String, ()> {
+    let mut bytes = [0u8; INVITE_CODE_LEN * 5 / 8];
+    SystemRandom::new().fill(&mut bytes).map_err(|_| {
+        error!("System random generator failed");
+    })?;
+    Ok(BASE32_NOPAD.encode(&bytes).to_lowercase())
+}
+
+async fn render_invites_page(state: &AppState, token: CsrfToken, error: Option<&str>) -> Response {
+    let csrf_token = if let Ok(csrf_token) = token.authenticity_token() {
+        csrf_token
+    } else {
+        return "Failed to get csrf token".into_response();
+    };
+
+    let db = &state.db;
+    let invites = if let Ok(invites) = db::get_invites(db).await {
+        invites
+    } else {
+        return "Failed to load invites".into_response();
+    };
+
+    let template = InvitesPageTemplate {
+        csrf_token: &csrf_token,
+        invites,
+        registration_mode: format!("{:?}", state.config.registration_mode).to_lowercase(),
+        error,
+        logged_in: true,
+    };
+    let reply_html = template.render().unwrap();
+    let status = if error.is_some() {
+        StatusCode::BAD_REQUEST
+    } else {
+        StatusCode::OK
+    };
+    (status, token, Html(reply_html)).into_response()
+}
+
+pub async fn invites_page(State(state): State<AppState>, token: CsrfToken) -> impl IntoResponse {
+    render_invites_page(&state, token, None).await
+}
+
+pub async fn invite_edit(
+    State(state): State<AppState>,
+    token: CsrfToken,
+    auth_session: AuthSession<AuthBackend>,
+    Form(form): Form<InviteEditForm>,
+) -> impl IntoResponse {
+    if token.verify(&form.csrf_token).is_err() {
+        return "Failed to verify csrf".into_response();
+    }
+
+    let admin = if let Some(admin) = auth_session.user {
+        admin
+    } else {
+        return "User not found".into_response();
+    };
+
+    let db = &state.db;
+    let result = match (form.action.as_str(), form.id) {
+        (CREATE_INVITE_ACTION, _) => {
+            let days = form.expires_days.unwrap_or(0);
+            let expires_at = days
+                .checked_mul(24 * 60 * 60)
+                .and_then(|secs| db::now().checked_add(secs));
+            if days < 0 || expires_at.is_none() {
+                Err("Invite lifetime is not valid")
+            } else {
+                let expires_at = expires_at.filter(|_| days > 0);
+                match generate_invite_code() {
+                    Ok(code) => db::create_invite(db, &code, admin.id, expires_at)
+                        .await
+                        .map_err(|_| "Failed to create invite"),
+                    Err(_) => Err("Failed to create invite"),
+                }
+            }
+        }
+        (DELETE_INVITE_ACTION, Some(invite_id)) => db::delete_unused_invite(db, invite_id)
+            .await
+            .map_err(|_| "Failed to delete invite"),
+        _ => Err("Unknown action"),
+    };
+
+    match result {
+        Ok(()) => Redirect::to("/admin/invites").into_response(),
+        Err(error) => render_invites_page(&state, token, Some(error)).await,
+    }
+}
+
+async fn render_filters_page(state: &AppState, token: CsrfToken, error: Option<&str>) -> Response {
+    let csrf_token = if let Ok(csrf_token) = token.authenticity_token() {
+        csrf_token
+    } else {
+        return "Failed to get csrf token".into_response();
+    };
+
+    let db = &state.db;
+    let rules = if let Ok(rules) = db::get_filter_rules(db).await {
+        rules
+    } else {
+        return "Failed to load filter rules".into_response();
+    };
+
+    let template = FiltersPageTemplate {
+        csrf_token: &csrf_token,
+        rules,
+        kinds: FilterRuleKind::ALL,
+        auto_approve: state.config.auto_approve,
+        error,
+        logged_in: true,
+    };
+    let reply_html = template.render().unwrap();
+    let status = if error.is_some() {
+        StatusCode::BAD_REQUEST
+    } else {
+        StatusCode::OK
+    };
+    (status, token, Html(reply_html)).into_response()
+}
+
+pub async fn filters_page(State(state): State<AppState>, token: CsrfToken) -> impl IntoResponse {
+    render_filters_page(&state, token, None).await
+}
+
+pub async fn filter_edit(
+    State(state): State<AppState>,
+    token: CsrfToken,
+    Form(form): Form<FilterEditForm>,
+) -> impl IntoResponse {
+    if token.verify(&form.csrf_token).is_err() {
+        return "Failed to verify csrf".into_response();
+    }
+
+    let db = &state.db;
+    let result = match (form.action.as_str(), form.id, form.kind, form.rule_action) {
+        (CREATE_RULE_ACTION, _, Some(kind), Some(rule_action)) => {
+            let pattern = form.pattern.as_deref().unwrap_or_default().trim();
+            if pattern.chars().count() > RULE_PATTERN_MAX_LEN {
+                Err("Pattern is too long")
+            } else if let Err(error) = content_filter::validate_rule(kind, pattern) {
+                Err(error)
+            } else {
+                db::create_filter_rule(db, kind, pattern, rule_action)
+                    .await
+                    .map_err(|_| "Failed to create rule")
+            }
+        }
+        (DELETE_RULE_ACTION, Some(rule_id), _, _) => db::delete_filter_rule(db, rule_id)
+            .await
+            .map_err(|_| "Failed to delete rule"),
+        _ => Err("Unknown action"),
+    };
+
+    match result {
+        Ok(()) => Redirect::to("/admin/filters").into_response(),
+        Err(error) => render_filters_page(&state, token, Some(error)).await,
+    }
+}
+
+#[derive(Template)]
+#[template(path = "admin_stats.html")]
+struct StatsPageTemplate {
+    users: UserStats,
+    adverts: AdvertStats,
+    latency: ModerationLatency,
+    registrations_chart: String,
+    postings_chart: String,
+    logged_in: bool,
+}
+
+pub async fn stats_page(State(state): State<AppState>, token: CsrfToken) -> impl IntoResponse {
+    let last_day = view_stats::day(db::now());
+    let first_day = last_day - view_stats::CHART_DAYS + 1;
+
+    let db = &state.db;
+    let (users, adverts, latency, registrations, postings) =
+        if let (Ok(users), Ok(adverts), Ok(latency), Ok(registrations), Ok(postings)) = (
+            db::get_user_stats(db).await,
+            db::get_advert_stats(db).await,
+            db::get_moderation_latency(db).await,
+            db::get_daily_registrations(db, first_day).await,
+            db::get_daily_postings(db, first_day).await,
+        ) {
+            (users, adverts, latency, registrations, postings)
+        } else {
+            return "Failed to load stats".into_response();
+        };
+
+    let template = StatsPageTemplate {
+        users,
+        adverts,
+        latency,
+        registrations_chart: view_stats::daily_chart(
+            "Registrations per day",
+            &registrations,
+            last_day,
+        ),
+        postings_chart: view_stats::daily_chart("New adverts per day", &postings, last_day),
+        logged_in: true,
+    };
+    let reply_html = template.render().unwrap();
+    (token, Html(reply_html)).into_response()
+}
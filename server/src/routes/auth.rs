@@ -1,17 +1,26 @@
+use std::net::SocketAddr;
+
 use askama::Template;
 use axum::{
-    extract::{Query, State},
-    http::StatusCode,
-    response::{Html, IntoResponse, Redirect},
+    extract::{ConnectInfo, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{Html, IntoResponse, Redirect, Response},
     Form,
 };
 use axum_csrf::CsrfToken;
 use axum_login::AuthSession;
 use serde::Deserialize;
+use tower_sessions::Session;
 
+use super::two_factor::start_pending_login;
 use crate::{
-    auth::{AuthBackend, Credentials},
-    db, AppState,
+    auth::{AuthBackend, AuthError, Credentials},
+    auth_models::User,
+    config::RegistrationMode,
+    db::{self, CreateUserError},
+    filters, metrics, redirect,
+    throttle::{self, ThrottleKeys},
+    AppState,
 };
 
 #[derive(Deserialize)]
@@ -19,40 +28,135 @@ pub struct NextUrl {
     pub next: Option<String>,
 }
 
+fn login_error(status: StatusCode, error: String, next: Option<String>) -> Response {
+    let template = LoginFormTemplate {
+        error: Some(error),
+        next,
+        logged_in: false,
+    };
+    let reply_html = template.render().unwrap();
+    (status, Html(reply_html)).into_response()
+}
+
 pub async fn login_with_password(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     mut auth_session: AuthSession<AuthBackend>,
+    session: Session,
     next: Query<NextUrl>,
     Form(creds): Form<Credentials>,
 ) -> impl IntoResponse {
+    let next_url = next.0.next.or(creds.next.clone());
+    let client_ip = state.config.client_ip(&addr, &headers);
+    let throttle_keys = throttle::keys(client_ip, &creds.username);
+
+    // Check locks before touching argon2, so locked clients can't burn CPU
+    match throttle::locked_until(&state.db, &throttle_keys).await {
+        Ok(Some(locked_until)) => {
+            state.metrics.login(metrics::LOGIN_THROTTLED);
+            return login_error(
+                StatusCode::TOO_MANY_REQUESTS,
+                format!(
+                    "Too many failed login attempts. {}",
+                    throttle::wait_message(locked_until)
+                ),
+                next_url,
+            );
+        }
+        Ok(None) => {}
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+
     let user = match auth_session.authenticate(creds.clone()).await {
         Ok(Some(user)) => user,
         Ok(None) => {
-            return StatusCode::UNAUTHORIZED.into_response();
+            state.metrics.login(metrics::LOGIN_FAILURE);
+            let db = &state.db;
+            throttle::record_failure(db, &throttle_keys).await;
+            return login_error(
+                StatusCode::UNAUTHORIZED,
+                "Wrong username or password".to_string(),
+                next_url,
+            );
+        }
+        Err(axum_login::Error::Backend(AuthError::PendingActivation)) => {
+            state.metrics.login(metrics::LOGIN_REJECTED);
+            return login_error(
+                StatusCode::FORBIDDEN,
+                "Your account is waiting for activation by a moderator".to_string(),
+                next_url,
+            );
+        }
+        Err(axum_login::Error::Backend(AuthError::Banned { until, reason })) => {
+            state.metrics.login(metrics::LOGIN_REJECTED);
+            let mut error = match until {
+                Some(until) => format!(
+                    "Your account has been banned until {}.",
+                    filters::format_datetime(until)
+                ),
+                None => "Your account has been banned.".to_string(),
+            };
+            if let Some(reason) = reason {
+                error.push_str(&format!(" Reason: {}", reason));
+            }
+            return login_error(StatusCode::FORBIDDEN, error, next_url);
         }
         Err(_) => {
             return StatusCode::INTERNAL_SERVER_ERROR.into_response();
         }
     };
 
-    if auth_session.login(&user).await.is_err() {
-        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    // Throttling is reset only after the second factor passed too
+    if user.totp_secret.is_some() {
+        if start_pending_login(&session, user.id, next_url)
+            .await
+            .is_err()
+        {
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+        return Redirect::to("/login/2fa").into_response();
     }
-    if let Some(next_url) = &next.next {
-        Redirect::to(&next_url)
-    } else {
-        Redirect::to("/")
+
+    finish_login(
+        &state,
+        &mut auth_session,
+        &user,
+        &throttle_keys,
+        next_url.as_deref(),
+    )
+    .await
+}
+
+pub(super) async fn finish_login(
+    state: &AppState,
+    auth_session: &mut AuthSession<AuthBackend>,
+    user: &User,
+    throttle_keys: &ThrottleKeys,
+    next_url: Option<&str>,
+) -> Response {
+    if auth_session.login(user).await.is_err() {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
     }
-    .into_response()
+    state.metrics.login(metrics::LOGIN_SUCCESS);
+
+    throttle::reset(&state.db, throttle_keys).await;
+    redirect::safe_redirect(next_url, "/").into_response()
 }
 
 #[derive(Template, Default)]
 #[template(path = "login.html")]
 pub struct LoginFormTemplate {
+    error: Option<String>,
+    next: Option<String>,
     logged_in: bool,
 }
 
-pub async fn login_form() -> impl IntoResponse {
-    let template = LoginFormTemplate::default();
+pub async fn login_form(Query(next): Query<NextUrl>) -> impl IntoResponse {
+    let template = LoginFormTemplate {
+        next: next.next.filter(|next| redirect::is_safe_redirect(next)),
+        ..Default::default()
+    };
     let reply_html = template.render().unwrap();
     (StatusCode::OK, Html(reply_html).into_response())
 }
@@ -62,28 +166,16 @@ pub async fn logout(mut auth_session: AuthSession<AuthBackend>) -> impl IntoResp
     Redirect::to("/")
 }
 
-pub async fn register(
-    State(state): State<AppState>,
-    token: CsrfToken,
-    Form(form): Form<RegisterForm>,
-) -> impl IntoResponse {
-    if let Err(_e) = token.verify(&form.csrf_token) {
-        "Error".into_response()
-    } else {
-        let db = state.db.write().await;
-        // Token is valid, register
-        if let Ok(_) = db::create_new_user(&db, &form.username, &form.password).await {
-            Redirect::to("/").into_response()
-        } else {
-            "Failed to register".into_response()
-        }
-    }
-}
-
-#[derive(Template)]
+#[derive(Template, Default)]
 #[template(path = "register.html")]
 pub struct RegisterFormTemplate<'a> {
     csrf_token: &'a str,
+    closed: bool,
+    invite_required: bool,
+    invite: &'a str,
+    username: &'a str,
+    error: Option<&'a str>,
+    notice: Option<&'a str>,
     logged_in: bool,
 }
 
@@ -92,15 +184,119 @@ pub struct RegisterForm {
     pub csrf_token: String,
     pub username: String,
     pub password: String,
+    pub invite: Option<String>,
 }
 
-pub async fn register_form(token: CsrfToken) -> impl IntoResponse {
-    let csrf_token = token.authenticity_token().unwrap();
+#[derive(Deserialize)]
+pub struct RegisterParams {
+    pub invite: Option<String>,
+}
+
+fn render_register(
+    state: &AppState,
+    token: CsrfToken,
+    status: StatusCode,
+    template: RegisterFormTemplate,
+) -> Response {
+    let csrf_token = if let Ok(csrf_token) = token.authenticity_token() {
+        csrf_token
+    } else {
+        return "Failed to get csrf token".into_response();
+    };
+    let mode = state.config.registration_mode;
     let template = RegisterFormTemplate {
         csrf_token: &csrf_token,
-        logged_in: false,
+        closed: mode == RegistrationMode::Closed,
+        invite_required: mode == RegistrationMode::Invite,
+        ..template
     };
     let reply_html = template.render().unwrap();
+    (status, token, Html(reply_html)).into_response()
+}
+
+pub async fn register(
+    State(state): State<AppState>,
+    token: CsrfToken,
+    Form(form): Form<RegisterForm>,
+) -> impl IntoResponse {
+    if let Err(_e) = token.verify(&form.csrf_token) {
+        return "Error".into_response();
+    }
 
-    (token, Html(reply_html)).into_response()
+    // Codes are generated in lowercase, pasted ones may come in any case
+    let invite = form
+        .invite
+        .as_deref()
+        .map(|invite| invite.trim().to_lowercase())
+        .filter(|invite| !invite.is_empty());
+    let invite = invite.as_deref();
+    let error_page = |status, error| {
+        let template = RegisterFormTemplate {
+            invite: invite.unwrap_or_default(),
+            username: &form.username,
+            error: Some(error),
+            ..Default::default()
+        };
+        render_register(&state, token.clone(), status, template)
+    };
+
+    let (active, invite) = match state.config.registration_mode {
+        RegistrationMode::Closed => {
+            return error_page(StatusCode::FORBIDDEN, "Registration is closed");
+        }
+        RegistrationMode::Invite => match invite {
+            Some(invite) => (true, Some(invite)),
+            None => return error_page(StatusCode::BAD_REQUEST, "Invite code is required"),
+        },
+        RegistrationMode::Open => (true, None),
+        RegistrationMode::Moderated => (false, None),
+    };
+    if form.username.trim().is_empty() || form.password.is_empty() {
+        return error_page(
+            StatusCode::BAD_REQUEST,
+            "Username and password must not be empty",
+        );
+    }
+
+    let result = db::create_new_user(
+        &state.db,
+        form.username.trim(),
+        &form.password,
+        &["users"],
+        active,
+        invite,
+    )
+    .await;
+    match result {
+        Ok(()) if active => Redirect::to("/login").into_response(),
+        Ok(()) => {
+            let template = RegisterFormTemplate {
+                notice: Some(
+                    "Your account was created and is waiting for activation by a moderator",
+                ),
+                ..Default::default()
+            };
+            render_register(&state, token, StatusCode::OK, template)
+        }
+        Err(CreateUserError::UsernameTaken) => {
+            error_page(StatusCode::CONFLICT, "This username is already taken")
+        }
+        Err(CreateUserError::InvalidInvite) => error_page(
+            StatusCode::BAD_REQUEST,
+            "Invite code is not valid or was already used",
+        ),
+        Err(CreateUserError::Internal) => "Failed to register".into_response(),
+    }
+}
+
+pub async fn register_form(
+    State(state): State<AppState>,
+    token: CsrfToken,
+    Query(params): Query<RegisterParams>,
+) -> impl IntoResponse {
+    let template = RegisterFormTemplate {
+        invite: params.invite.as_deref().unwrap_or_default(),
+        ..Default::default()
+    };
+    render_register(&state, token, StatusCode::OK, template)
 }
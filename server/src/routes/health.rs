@@ -0,0 +1,71 @@
+use std::path::Path;
+
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use serde::Serialize;
+
+use crate::{db, AppState, DB_FILE};
+
+const OK: &str = "ok";
+const FAILED: &str = "failed";
+const PROBE_FILE: &str = ".readyz";
+
+#[derive(Serialize)]
+struct Health {
+    status: &'static str,
+}
+
+#[derive(Serialize)]
+struct Readiness {
+    status: &'static str,
+    database: &'static str,
+    migrations: &'static str,
+    disk: &'static str,
+}
+
+fn check(ok: bool) -> &'static str {
+    if ok {
+        OK
+    } else {
+        FAILED
+    }
+}
+
+/// Liveness, answers as long as the process serves requests
+pub async fn healthz() -> impl IntoResponse {
+    Json(Health { status: OK })
+}
+
+/// Whether a file can be created next to the database
+async fn disk_writable() -> bool {
+    let dir = Path::new(DB_FILE)
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let probe = dir.join(PROBE_FILE);
+    tokio::fs::write(&probe, b"").await.is_ok() && tokio::fs::remove_file(&probe).await.is_ok()
+}
+
+/// Readiness, the database answers, its schema is current and the disk takes writes
+pub async fn readyz(State(state): State<AppState>) -> impl IntoResponse {
+    let (database, migrations) = {
+        let db = &state.db;
+        let database = db::ping(db).await.is_ok();
+        let migrations = database && db::migrations_current(db).await.unwrap_or(false);
+        (database, migrations)
+    };
+    let disk = disk_writable().await;
+
+    let ready = database && migrations && disk;
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    let readiness = Readiness {
+        status: check(ready),
+        database: check(database),
+        migrations: check(migrations),
+        disk: check(disk),
+    };
+    (status, Json(readiness))
+}
@@ -1,29 +1,67 @@
+use std::net::SocketAddr;
+
 use askama::Template;
 use axum::{
-    extract::{Path, State},
-    response::{Html, IntoResponse, Redirect},
+    extract::{ConnectInfo, Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{Html, IntoResponse, Redirect, Response},
     Form,
 };
 use axum_csrf::CsrfToken;
 use axum_login::{AuthSession, AuthzBackend};
 use serde::Deserialize;
+use sqlx::{Pool, Sqlite};
+use tracing::info;
 
 use crate::{
     auth::{AuthBackend, AuthPermission},
-    db,
-    models::Advert,
-    AppState,
+    auth_models::User,
+    content_filter,
+    db::{self, CreateAdvertError},
+    filters, markdown,
+    models::{Advert, ReportReason, Seller},
+    view_stats, AppState,
 };
 
+const REPORT_DETAILS_MAX_LEN: usize = 1000;
+const SELLER_ADVERTS_LIMIT: i64 = 5;
+const SIMILAR_ADVERTS_LIMIT: i64 = 5;
+
 #[derive(Template)]
 #[template(path = "item.html")]
 pub struct ItemPageTemplate {
     csrf_token: String,
     advert: Advert,
+    /// Sanitized html rendered from the markdown content
+    content_html: String,
     own_advert: bool,
+    /// Moderators see adverts as their own, only the author can edit
+    author: bool,
+    /// Near-duplicates, shown to moderators only
+    duplicates: Vec<i64>,
+    /// `None` for adverts whose author was lost
+    seller: Option<Seller>,
+    /// Other published adverts of the seller
+    seller_adverts: Vec<Advert>,
+    /// Published adverts with matching words in the title
+    similar_adverts: Vec<Advert>,
+    reasons: [ReportReason; 5],
+    reported: bool,
     logged_in: bool,
 }
 
+#[derive(Deserialize)]
+pub struct ItemPageParams {
+    reported: Option<bool>,
+}
+
+#[derive(Deserialize)]
+pub struct ItemReportForm {
+    csrf_token: String,
+    reason: ReportReason,
+    details: String,
+}
+
 #[derive(Deserialize)]
 pub struct ItemEditForm {
     csrf_token: String,
@@ -46,22 +84,22 @@ pub async fn item_page_edit(
         return "No user found".into_response();
     };
 
-    let db: tokio::sync::RwLockWriteGuard<'_, sqlx::Pool<sqlx::Sqlite>> = state.db.write().await;
-    if let Ok(is_own_advert) = db::check_advert_belong_to_user(&db, user_id, advert_id).await {
+    let db = &state.db;
+    if let Ok(is_own_advert) = db::check_advert_belong_to_user(db, user_id, advert_id).await {
         if is_own_advert {
-            if db::toggle_advert_publish(&db, advert_id, false)
+            if db::toggle_advert_publish(db, advert_id, false)
                 .await
                 .is_ok()
             {
-                return Redirect::to(&format!("/item/{}", advert_id)).into_response();
+                Redirect::to(&format!("/item/{}", advert_id)).into_response()
             } else {
-                return "Failed to unpublish advert".into_response();
+                "Failed to unpublish advert".into_response()
             }
         } else {
-            return "You tried edit someone else advert".into_response();
+            "You tried edit someone else advert".into_response()
         }
     } else {
-        return "You tried edit someone else advert".into_response();
+        "You tried edit someone else advert".into_response()
     }
 }
 
@@ -69,7 +107,10 @@ pub async fn item_page(
     State(state): State<AppState>,
     token: CsrfToken,
     auth_session: AuthSession<AuthBackend>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     Path(item_id): Path<i64>,
+    Query(params): Query<ItemPageParams>,
 ) -> impl IntoResponse {
     let csrf_token = if let Ok(token) = token.authenticity_token() {
         token
@@ -86,7 +127,7 @@ pub async fn item_page(
             .has_perm(
                 &user,
                 AuthPermission {
-                    name: "admin.read".to_string(),
+                    name: "mod.read".to_string(),
                 },
             )
             .await
@@ -94,29 +135,230 @@ pub async fn item_page(
     } else {
         false
     };
-    let db = state.db.read().await;
-    let (advert, own_advert) =
-        if let Ok(advert) = db::get_advert_by_id(&db, user_id, item_id, is_admin).await {
+    let db = &state.db;
+    let (mut advert, own_advert) =
+        if let Ok(advert) = db::get_advert_by_id(db, user_id, item_id, is_admin).await {
             advert
         } else {
             return "Not found".into_response();
         };
 
+    let author = user_id.is_some() && advert.user_id == user_id;
+
+    let duplicates = match (is_admin, advert.fingerprint) {
+        (true, Some(fingerprint)) => {
+            db::get_recent_advert_fingerprints(db, content_filter::DUPLICATE_CANDIDATES_LIMIT)
+                .await
+                .map(|fingerprints| {
+                    content_filter::near_duplicates(fingerprint, advert.id, &fingerprints)
+                })
+                .unwrap_or_default()
+        }
+        _ => vec![],
+    };
+
+    // Authors looking at their own advert and bots don't count as views
+    let user_agent = headers
+        .get(header::USER_AGENT)
+        .and_then(|user_agent| user_agent.to_str().ok());
+    if advert.published && !author && !view_stats::is_bot(user_agent) {
+        let user_agent = user_agent.unwrap_or_default();
+        let client_ip = state.config.client_ip(&addr, &headers);
+        let viewer = view_stats::viewer_key(user_id, client_ip, user_agent, db::now());
+        if let Ok(true) = db::record_advert_view(db, advert.id, &viewer).await {
+            advert.views += 1;
+        }
+    }
+
+    let (seller, seller_adverts, similar_adverts) = if let (Ok(seller), Ok(similar_adverts)) = (
+        db::get_advert_seller(db, advert.id).await,
+        db::get_similar_adverts(db, &advert.title, advert.id, SIMILAR_ADVERTS_LIMIT).await,
+    ) {
+        let seller_adverts = match &seller {
+            Some(seller) => {
+                if let Ok(adverts) =
+                    db::get_seller_adverts(db, seller.id, advert.id, SELLER_ADVERTS_LIMIT).await
+                {
+                    adverts
+                } else {
+                    return "Failed to load seller adverts".into_response();
+                }
+            }
+            None => vec![],
+        };
+        (seller, seller_adverts, similar_adverts)
+    } else {
+        return "Failed to load advert info".into_response();
+    };
+
     let template = ItemPageTemplate {
         csrf_token,
+        content_html: markdown::render(&advert.content),
         advert,
         own_advert,
+        author,
+        duplicates,
+        seller,
+        seller_adverts,
+        similar_adverts,
+        reasons: ReportReason::ALL,
+        reported: params.reported.unwrap_or(false),
         logged_in,
     };
     let reply_html = template.render().unwrap();
     (token, Html(reply_html).into_response()).into_response()
 }
 
+pub async fn item_report(
+    State(state): State<AppState>,
+    token: CsrfToken,
+    auth_session: AuthSession<AuthBackend>,
+    Path(advert_id): Path<i64>,
+    Form(form): Form<ItemReportForm>,
+) -> impl IntoResponse {
+    if token.verify(&form.csrf_token).is_err() {
+        return "Failed to verify csrf".into_response();
+    }
+
+    let user = if let Some(user) = auth_session.user {
+        user
+    } else {
+        return "User not found".into_response();
+    };
+
+    let details = form.details.trim();
+    if details.chars().count() > REPORT_DETAILS_MAX_LEN {
+        return (StatusCode::BAD_REQUEST, "Report details are too long").into_response();
+    }
+    let details = Some(details).filter(|details| !details.is_empty());
+
+    let db = &state.db;
+    // Only adverts visible to the reporter can be reported, and not own ones
+    match db::get_advert_by_id(db, Some(user.id), advert_id, false).await {
+        Ok((advert, false)) if advert.published => {}
+        _ => return (StatusCode::NOT_FOUND, "Not found").into_response(),
+    }
+
+    let reports_count =
+        if let Ok(count) = db::create_report(db, advert_id, user.id, form.reason, details).await {
+            count
+        } else {
+            return "Failed to send report".into_response();
+        };
+    let threshold = state.config.report_threshold;
+    if threshold > 0 && reports_count >= threshold {
+        info!(advert_id, reports_count, "Advert unpublished after reports");
+        if db::toggle_advert_publish(db, advert_id, false)
+            .await
+            .is_err()
+        {
+            return "Failed to unpublish advert".into_response();
+        }
+        // The reporter can't see the advert anymore, so the notice is shown on the board
+        return Redirect::to("/?reported=true").into_response();
+    }
+
+    Redirect::to(&format!("/item/{}?reported=true", advert_id)).into_response()
+}
+
 #[derive(Deserialize)]
 pub struct ItemNewForm {
     pub title: String,
     pub content: String,
     pub csrf_token: String,
+    /// Set by the preview submit button, the advert is rendered but not saved
+    pub preview: Option<String>,
+}
+
+#[derive(Template, Default)]
+#[template(path = "item_new.html")]
+pub struct ItemNewFormTemplate<'a> {
+    pub csrf_token: &'a str,
+    /// Set when an existing advert is edited
+    advert_id: Option<i64>,
+    title: &'a str,
+    content: &'a str,
+    /// Rendered content for the preview
+    preview: Option<String>,
+    error: Option<&'a str>,
+    logged_in: bool,
+}
+
+fn render_item_form(
+    token: CsrfToken,
+    advert_id: Option<i64>,
+    title: &str,
+    content: &str,
+    error: Option<&str>,
+) -> Response {
+    render_item_form_with_preview(token, advert_id, title, content, None, error)
+}
+
+fn render_item_form_with_preview(
+    token: CsrfToken,
+    advert_id: Option<i64>,
+    title: &str,
+    content: &str,
+    preview: Option<String>,
+    error: Option<&str>,
+) -> Response {
+    let csrf_token = if let Ok(csrf_token) = token.authenticity_token() {
+        csrf_token
+    } else {
+        return "Failed to get csrf token".into_response();
+    };
+    let template = ItemNewFormTemplate {
+        csrf_token: &csrf_token,
+        advert_id,
+        title,
+        content,
+        preview,
+        error,
+        logged_in: true,
+    };
+    let reply_html = template.render().unwrap();
+    let status = if error.is_some() {
+        StatusCode::BAD_REQUEST
+    } else {
+        StatusCode::OK
+    };
+    (status, token, Html(reply_html)).into_response()
+}
+
+const REJECTED_ERROR: &str = "Advert contains prohibited content and can't be published";
+
+/// Error to show if the account is too new to post adverts
+fn account_age_error(state: &AppState, user: &User) -> Option<String> {
+    let min_age = state.config.min_account_age_hours.saturating_mul(60 * 60);
+    let wait = user.created_at.saturating_add(min_age) - db::now();
+    if min_age > 0 && wait > 0 {
+        Some(format!(
+            "New accounts can't post adverts yet, try again in {} hour(s)",
+            wait.saturating_add(60 * 60 - 1) / (60 * 60)
+        ))
+    } else {
+        None
+    }
+}
+
+/// Error to show if reposting own published adverts is blocked and this is one
+async fn check_own_duplicate(
+    state: &AppState,
+    db: &Pool<Sqlite>,
+    user_id: i64,
+    advert_id: Option<i64>,
+    fingerprint: i64,
+) -> Option<String> {
+    if !state.config.block_own_duplicates {
+        return None;
+    }
+    match content_filter::own_near_duplicate(db, user_id, advert_id, fingerprint).await {
+        Ok(Some(duplicate_id)) => Some(format!(
+            "You already have a similar published advert #{}, edit it instead",
+            duplicate_id
+        )),
+        _ => None,
+    }
 }
 
 pub async fn item_new(
@@ -134,38 +376,174 @@ pub async fn item_new(
     if let Err(_e) = token.verify(&form.csrf_token) {
         return "Error".into_response();
     }
-    let db = state.db.write().await;
-    let new_advert_id =
-        if let Ok(id) = db::create_new_advert(&db, user.id, &form.title, &form.content).await {
-            id
-        } else {
-            return "Failed to create advert".into_response();
-        };
+    if form.preview.is_some() {
+        let preview = markdown::render(&form.content);
+        return render_item_form_with_preview(
+            token,
+            None,
+            &form.title,
+            &form.content,
+            Some(preview),
+            None,
+        );
+    }
+    if let Some(error) = account_age_error(&state, &user) {
+        return render_item_form(token, None, &form.title, &form.content, Some(&error));
+    }
+    let db = &state.db;
+    let checked = match content_filter::moderate(db, None, &form.title, &form.content).await {
+        Ok(checked) => checked,
+        Err(_) => return "Failed to create advert".into_response(),
+    };
+    if let Some(error) = check_own_duplicate(&state, db, user.id, None, checked.fingerprint).await {
+        return render_item_form(token, None, &form.title, &form.content, Some(&error));
+    }
+    let moderation = match checked.into_moderation(state.config.auto_approve) {
+        Ok(moderation) => moderation,
+        Err(rule) => {
+            info!(user_id = user.id, %rule, "New advert rejected by filter rule");
+            return render_item_form(
+                token,
+                None,
+                &form.title,
+                &form.content,
+                Some(REJECTED_ERROR),
+            );
+        }
+    };
+    let quota_error = match db::create_new_advert(
+        db,
+        user.id,
+        &form.title,
+        &form.content,
+        &moderation,
+        &state.config.advert_quota(),
+    )
+    .await
+    {
+        Ok(new_advert_id) => {
+            return Redirect::to(&format!("/item/{}", new_advert_id)).into_response()
+        }
+        Err(CreateAdvertError::PublishedQuota(limit)) => format!(
+            "You can have at most {} published or pending adverts, unpublish one before posting a new one",
+            limit
+        ),
+        Err(CreateAdvertError::HourlyQuota(limit)) => format!(
+            "You can post at most {} adverts per hour, try again later",
+            limit
+        ),
+        Err(CreateAdvertError::DailyQuota(limit)) => format!(
+            "You can post at most {} adverts per day, try again tomorrow",
+            limit
+        ),
+        Err(CreateAdvertError::Internal) => return "Failed to create advert".into_response(),
+    };
+    render_item_form(token, None, &form.title, &form.content, Some(&quota_error))
+}
 
-    Redirect::to(&format!("/item/{}", new_advert_id)).into_response()
+pub async fn item_new_form(
+    State(state): State<AppState>,
+    token: CsrfToken,
+    auth_session: AuthSession<AuthBackend>,
+) -> impl IntoResponse {
+    // Tell about the account age up front instead of after the advert was written
+    let error = auth_session
+        .user
+        .and_then(|user| account_age_error(&state, &user));
+    render_item_form(token, None, "", "", error.as_deref())
 }
 
-#[derive(Template)]
-#[template(path = "item_new.html")]
-pub struct ItemNewFormTemplate<'a> {
-    pub csrf_token: &'a str,
-    logged_in: bool,
+pub async fn item_edit_form(
+    State(state): State<AppState>,
+    token: CsrfToken,
+    auth_session: AuthSession<AuthBackend>,
+    Path(advert_id): Path<i64>,
+) -> impl IntoResponse {
+    let user = if let Some(user) = auth_session.user {
+        user
+    } else {
+        return "User not found".into_response();
+    };
+
+    let db = &state.db;
+    match db::get_advert_by_id(db, Some(user.id), advert_id, false).await {
+        Ok((advert, true)) => {
+            render_item_form(token, Some(advert.id), &advert.title, &advert.content, None)
+        }
+        _ => (StatusCode::NOT_FOUND, "Not found").into_response(),
+    }
 }
 
-pub async fn item_new_form(
+/// Edits go through the content filter again, so a reviewed advert can't be swapped
+/// for something else afterwards
+pub async fn item_edit(
+    State(state): State<AppState>,
     token: CsrfToken,
     auth_session: AuthSession<AuthBackend>,
+    Path(advert_id): Path<i64>,
+    Form(form): Form<ItemNewForm>,
 ) -> impl IntoResponse {
-    let csrf_token = if let Ok(csrf_token) = token.authenticity_token() {
-        csrf_token
+    if token.verify(&form.csrf_token).is_err() {
+        return "Failed to verify csrf".into_response();
+    }
+
+    let user = if let Some(user) = auth_session.user {
+        user
     } else {
-        return "Failed to get csrf token".into_response();
+        return "User not found".into_response();
     };
-    let logged_in = auth_session.user.is_some();
-    let template = ItemNewFormTemplate {
-        csrf_token: &csrf_token,
-        logged_in,
+
+    let db = &state.db;
+    let advert = match db::get_advert_by_id(db, Some(user.id), advert_id, false).await {
+        Ok((advert, true)) => advert,
+        _ => return (StatusCode::NOT_FOUND, "Not found").into_response(),
     };
-    let reply_html = template.render().unwrap();
-    (token, Html(reply_html)).into_response()
+    if form.preview.is_some() {
+        let preview = markdown::render(&form.content);
+        return render_item_form_with_preview(
+            token,
+            Some(advert_id),
+            &form.title,
+            &form.content,
+            Some(preview),
+            None,
+        );
+    }
+    let checked =
+        match content_filter::moderate(db, Some(advert_id), &form.title, &form.content).await {
+            Ok(checked) => checked,
+            Err(_) => return "Failed to save advert".into_response(),
+        };
+    if let Some(error) =
+        check_own_duplicate(&state, db, user.id, Some(advert_id), checked.fingerprint).await
+    {
+        return render_item_form(
+            token,
+            Some(advert_id),
+            &form.title,
+            &form.content,
+            Some(&error),
+        );
+    }
+    let moderation = match checked.into_moderation(advert.published && state.config.auto_approve) {
+        Ok(moderation) => moderation,
+        Err(rule) => {
+            info!(advert_id, %rule, "Advert edit rejected by filter rule");
+            return render_item_form(
+                token,
+                Some(advert_id),
+                &form.title,
+                &form.content,
+                Some(REJECTED_ERROR),
+            );
+        }
+    };
+    if db::update_advert(db, advert_id, &form.title, &form.content, &moderation)
+        .await
+        .is_err()
+    {
+        return "Failed to save advert".into_response();
+    }
+
+    Redirect::to(&format!("/item/{}", advert_id)).into_response()
 }
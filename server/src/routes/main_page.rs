@@ -8,22 +8,29 @@ use axum::{
 use axum_login::AuthSession;
 use serde::Deserialize;
 
-use crate::{auth::AuthBackend, db, models::Advert, AppState};
+use crate::{
+    auth::AuthBackend,
+    db,
+    models::Advert,
+    pagination::{Cursor, Page, PageRequest},
+    AppState,
+};
 
 const MAIN_PAGE_LIMIT: i64 = 10;
 
 #[derive(Template)]
 #[template(path = "main.html")]
 pub struct MainPageTemplate {
-    adverts: Vec<Advert>,
-    total_pages: i64,
-    page: i64,
+    adverts: Page<Advert>,
+    /// The advert the user reported got hidden until review
+    reported: bool,
     logged_in: bool,
 }
 
 #[derive(Deserialize)]
 pub struct MainPageParams {
-    page: Option<i64>,
+    page: Option<String>,
+    reported: Option<bool>,
 }
 
 pub async fn main_board(
@@ -31,24 +38,19 @@ pub async fn main_board(
     Query(params): Query<MainPageParams>,
     auth_session: AuthSession<AuthBackend>,
 ) -> impl IntoResponse {
-    let page = params.page.unwrap_or(1);
-    let per_page = MAIN_PAGE_LIMIT;
-    let offset = (page - 1) * per_page;
+    let page = PageRequest::new(Cursor::parse(params.page.as_deref()), MAIN_PAGE_LIMIT);
 
-    let db = state.db.read().await;
-    let (adverts, total_count) =
-        if let Ok(adverts) = db::get_main_page(&db, MAIN_PAGE_LIMIT, offset).await {
-            adverts
-        } else {
-            return "Main page error".into_response();
-        };
-    let total_pages = (total_count as f64 / per_page as f64).ceil() as i64;
+    let db = &state.db;
+    let adverts = if let Ok(adverts) = db::get_main_page(db, &page).await {
+        adverts
+    } else {
+        return "Main page error".into_response();
+    };
 
     let logged_in = auth_session.user.is_some();
     let template = MainPageTemplate {
         adverts,
-        total_pages,
-        page,
+        reported: params.reported.unwrap_or(false),
         logged_in,
     };
     let reply_html = template.render().unwrap();
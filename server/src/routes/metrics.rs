@@ -0,0 +1,41 @@
+use axum::{
+    extract::State,
+    http::{header, HeaderMap, StatusCode},
+    response::IntoResponse,
+};
+
+use crate::AppState;
+
+fn authorized(expected_token: &str, headers: &HeaderMap) -> bool {
+    let token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match token {
+        Some(token) => ring::constant_time::verify_slices_are_equal(
+            token.as_bytes(),
+            expected_token.as_bytes(),
+        )
+        .is_ok(),
+        None => false,
+    }
+}
+
+pub async fn metrics_page(State(state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    // Without a token the endpoint doesn't exist
+    let expected_token = if let Some(token) = &state.config.metrics_token {
+        token
+    } else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    if !authorized(expected_token, &headers) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+
+    let db = &state.db;
+    if let Ok((content_type, body)) = state.metrics.render(db).await {
+        ([(header::CONTENT_TYPE, content_type)], body).into_response()
+    } else {
+        StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    }
+}
@@ -1,11 +1,29 @@
+mod admin;
 mod auth;
+mod health;
 mod item;
 mod main_page;
+mod metrics;
 mod moderator;
 mod profile;
+mod settings;
+mod two_factor;
 
+pub use admin::{
+    filter_edit, filters_page, group_edit, group_new, group_page, groups_page, invite_edit,
+    invites_page, stats_page, user_edit, user_page,
+};
 pub use auth::{login_form, login_with_password, logout, register, register_form};
-pub use item::{item_new, item_new_form, item_page, item_page_edit};
+pub use health::{healthz, readyz};
+pub use item::{
+    item_edit, item_edit_form, item_new, item_new_form, item_page, item_page_edit, item_report,
+};
 pub use main_page::main_board;
-pub use moderator::{mod_edit, mod_page};
+pub use metrics::metrics_page;
+pub use moderator::{mod_edit, mod_page, mod_users_edit};
 pub use profile::profile;
+pub use settings::{settings_delete, settings_edit, settings_page, settings_password};
+pub use two_factor::{
+    login_two_factor, login_two_factor_form, require_admin_two_factor, two_factor_disable,
+    two_factor_enable, two_factor_page,
+};
@@ -6,22 +6,33 @@ use axum::{
     Form,
 };
 use axum_csrf::CsrfToken;
+use axum_login::{AuthSession, AuthzBackend};
 use serde::Deserialize;
 
-use crate::{auth_models::User, db, models::Advert, AppState};
+use crate::{
+    auth::{AuthBackend, AuthPermission},
+    auth_models::User,
+    content_filter, db, filters,
+    models::{Advert, LoginLock, ReportedAdvert},
+    pagination::{Cursor, Page, PageRequest},
+    AppState,
+};
 
 const ADVERTS_LIMIT: i64 = 10;
 const USERS_LIMIT: i64 = 10;
 
-const ACTIVATE_USER_ACTION: &'static str = "au";
-const DEACTIVATE_USER_ACTION: &'static str = "du";
-const PUBLISH_ADVERT_ACTION: &'static str = "pa";
-const UNPUBLISH_ADVERT_ACTION: &'static str = "ua";
+const ACTIVATE_USER_ACTION: &str = "au";
+const DEACTIVATE_USER_ACTION: &str = "du";
+const PUBLISH_ADVERT_ACTION: &str = "pa";
+const UNPUBLISH_ADVERT_ACTION: &str = "ua";
+const UNLOCK_LOGIN_ACTION: &str = "ul";
+const DISMISS_REPORTS_ACTION: &str = "dr";
+const UPHOLD_REPORTS_ACTION: &str = "hr";
 
 #[derive(Deserialize)]
 pub struct ModPageParams {
-    user_page: Option<i64>,
-    advert_page: Option<i64>,
+    user_page: Option<String>,
+    advert_page: Option<String>,
 }
 
 #[derive(Template)]
@@ -29,20 +40,21 @@ pub struct ModPageParams {
 struct ModeratorPageTemplate {
     csrf_token: String,
 
-    adverts: Vec<Advert>,
-    users: Vec<User>,
-
-    advert_page: i64,
-    total_advert_pages: i64,
-    user_page: i64,
-    total_user_pages: i64,
+    /// Adverts with ids of their near-duplicates
+    adverts: Page<(Advert, Vec<i64>)>,
+    reports: Vec<ReportedAdvert>,
+    users: Page<User>,
+    locked_logins: Vec<LoginLock>,
+    now: i64,
 
+    manage_users: bool,
     logged_in: bool,
 }
 
 pub async fn mod_page(
     State(state): State<AppState>,
     token: CsrfToken,
+    auth_session: AuthSession<AuthBackend>,
     Query(params): Query<ModPageParams>,
 ) -> impl IntoResponse {
     let csrf_token = if let Ok(csrf_token) = token.authenticity_token() {
@@ -51,55 +63,67 @@ pub async fn mod_page(
         return "Failed to get csrf token".into_response();
     };
 
-    let advert_page = params.advert_page.unwrap_or(1);
-    let user_page = params.user_page.unwrap_or(1);
-    let adverts_per_page = ADVERTS_LIMIT;
-    let users_per_page = USERS_LIMIT;
-    let adverts_offset = (advert_page - 1) * adverts_per_page;
-    let users_offset = (user_page - 1) * users_per_page;
-
-    let db = state.db.read().await;
-    let ((adverts, adverts_total_count), (users, users_total_count)) = if let Ok((adverts, users)) =
-        db::get_mod_page(
-            &db,
-            adverts_offset,
-            ADVERTS_LIMIT,
-            users_offset,
-            USERS_LIMIT,
-        )
-        .await
-    {
-        (adverts, users)
+    // Moderators see only adverts, users are managed by admins
+    let manage_users = if let Some(user) = &auth_session.user {
+        auth_session
+            .backend
+            .has_perm(user, AuthPermission::from("admin.read"))
+            .await
+            .unwrap_or(false)
     } else {
-        return "Failed to load mod page info".into_response();
+        false
     };
 
-    let total_advert_pages = (adverts_total_count as f64 / adverts_per_page as f64).ceil() as i64;
-    let total_user_pages = (users_total_count as f64 / users_per_page as f64).ceil() as i64;
+    let advert_page = PageRequest::new(Cursor::parse(params.advert_page.as_deref()), ADVERTS_LIMIT);
+    let user_page = PageRequest::new(Cursor::parse(params.user_page.as_deref()), USERS_LIMIT);
+
+    let db = &state.db;
+    let (adverts, reports, fingerprints) = if let (Ok(adverts), Ok(reports), Ok(fingerprints)) = (
+        db::get_mod_adverts(db, &advert_page).await,
+        db::get_reported_adverts(db).await,
+        db::get_recent_advert_fingerprints(db, content_filter::DUPLICATE_CANDIDATES_LIMIT).await,
+    ) {
+        (adverts, reports, fingerprints)
+    } else {
+        return "Failed to load mod page info".into_response();
+    };
+    let adverts = adverts.map(|advert| {
+        let duplicates = advert
+            .fingerprint
+            .map(|fingerprint| {
+                content_filter::near_duplicates(fingerprint, advert.id, &fingerprints)
+            })
+            .unwrap_or_default();
+        (advert, duplicates)
+    });
+
+    let (users, locked_logins) = if manage_users {
+        if let (Ok(users), Ok(locked_logins)) = (
+            db::get_mod_users(db, &user_page).await,
+            db::get_locked_logins(db).await,
+        ) {
+            (users, locked_logins)
+        } else {
+            return "Failed to load mod page info".into_response();
+        }
+    } else {
+        (user_page.page(vec![]), vec![])
+    };
 
     let template = ModeratorPageTemplate {
         csrf_token,
         adverts,
+        reports,
         users,
-        advert_page,
-        total_advert_pages,
-        user_page,
-        total_user_pages,
+        locked_logins,
+        now: db::now(),
+        manage_users,
         logged_in: true,
     };
     let reply_html = template.render().unwrap();
     (token, Html(reply_html).into_response()).into_response()
 }
 
-#[derive(Deserialize)]
-#[serde(tag = "action")]
-pub enum ModAction {
-    ActivateUser,
-    DeactivateUser,
-    PublishAdvert,
-    UnpublishAdvert,
-}
-
 #[derive(Deserialize)]
 
 pub struct ModEditForm {
@@ -108,6 +132,14 @@ pub struct ModEditForm {
     id: i64,
 }
 
+fn mod_page_redirect(params: &ModPageParams) -> Redirect {
+    Redirect::to(&format!(
+        "/mod?advert_page={}&user_page={}",
+        Cursor::param(Cursor::parse(params.advert_page.as_deref())),
+        Cursor::param(Cursor::parse(params.user_page.as_deref()))
+    ))
+}
+
 pub async fn mod_edit(
     State(state): State<AppState>,
     token: CsrfToken,
@@ -118,22 +150,46 @@ pub async fn mod_edit(
         return "Failed to verify csrf".into_response();
     }
 
-    let db = state.db.write().await;
+    let db = &state.db;
+
+    let result = match form.action.as_str() {
+        PUBLISH_ADVERT_ACTION => db::moderate_advert(db, form.id, true).await,
+        UNPUBLISH_ADVERT_ACTION => db::moderate_advert(db, form.id, false).await,
+        // Dismissed reports leave the advert as it is, publish it back separately if needed
+        DISMISS_REPORTS_ACTION => db::resolve_reports(db, form.id).await,
+        UPHOLD_REPORTS_ACTION => match db::resolve_reports(db, form.id).await {
+            Ok(()) => db::moderate_advert(db, form.id, false).await,
+            Err(()) => Err(()),
+        },
+        _ => Err(()),
+    };
+    if result.is_ok() {
+        mod_page_redirect(&params).into_response()
+    } else {
+        "Failed to proceed mod action".into_response()
+    }
+}
+
+pub async fn mod_users_edit(
+    State(state): State<AppState>,
+    token: CsrfToken,
+    Query(params): Query<ModPageParams>,
+    Form(form): Form<ModEditForm>,
+) -> impl IntoResponse {
+    if token.verify(&form.csrf_token).is_err() {
+        return "Failed to verify csrf".into_response();
+    }
+
+    let db = &state.db;
 
     let result = match form.action.as_str() {
-        ACTIVATE_USER_ACTION => db::toggle_user_active(&db, form.id, true).await,
-        DEACTIVATE_USER_ACTION => db::toggle_user_active(&db, form.id, false).await,
-        PUBLISH_ADVERT_ACTION => db::toggle_advert_publish(&db, form.id, true).await,
-        UNPUBLISH_ADVERT_ACTION => db::toggle_advert_publish(&db, form.id, false).await,
+        ACTIVATE_USER_ACTION => db::toggle_user_active(db, form.id, true).await,
+        DEACTIVATE_USER_ACTION => db::toggle_user_active(db, form.id, false).await,
+        UNLOCK_LOGIN_ACTION => db::unlock_login(db, form.id).await,
         _ => Err(()),
     };
     if result.is_ok() {
-        Redirect::to(&format!(
-            "/mod?advert_page={}&user_page={}",
-            params.advert_page.unwrap_or(1),
-            params.user_page.unwrap_or(1)
-        ))
-        .into_response()
+        mod_page_redirect(&params).into_response()
     } else {
         "Failed to proceed mod action".into_response()
     }
@@ -8,22 +8,27 @@ use axum_csrf::CsrfToken;
 use axum_login::AuthSession;
 use serde::Deserialize;
 
-use crate::{auth::AuthBackend, db, models::Advert, AppState};
+use crate::{
+    auth::AuthBackend,
+    db,
+    models::Advert,
+    pagination::{Cursor, Page, PageRequest},
+    view_stats, AppState,
+};
 
 const PROFILE_PAGE_LIMIT: i64 = 10;
 
 #[derive(Template)]
 #[template(path = "profile.html")]
 pub struct ProfilePageTemplate {
-    adverts: Vec<Advert>,
-    total_pages: i64,
-    page: i64,
+    /// Adverts with svg charts of their daily views
+    adverts: Page<(Advert, String)>,
     logged_in: bool,
 }
 
 #[derive(Deserialize)]
 pub struct ProfilePageParams {
-    page: Option<i64>,
+    page: Option<String>,
 }
 
 pub async fn profile(
@@ -38,25 +43,35 @@ pub async fn profile(
         return "User not found".into_response();
     };
 
-    let page = path.page.unwrap_or(1);
-    let per_page = PROFILE_PAGE_LIMIT;
-    let offset = (page - 1) * per_page;
+    let page = PageRequest::new(Cursor::parse(path.page.as_deref()), PROFILE_PAGE_LIMIT);
 
-    let db = state.db.read().await;
-    let (adverts, total_count) = if let Ok((adverts, total_count)) =
-        db::get_user_adverts(&db, user.id, offset, per_page).await
-    {
-        (adverts, total_count)
+    let db = &state.db;
+    let adverts = if let Ok(adverts) = db::get_user_adverts(db, user.id, &page).await {
+        adverts
     } else {
         return "Failed to load profile".into_response();
     };
 
-    let total_pages = (total_count as f64 / per_page as f64).ceil() as i64;
+    let last_day = view_stats::day(db::now());
+    let first_day = last_day - view_stats::CHART_DAYS + 1;
+    let daily_views =
+        if let Ok(daily_views) = db::get_user_daily_views(db, user.id, first_day).await {
+            daily_views
+        } else {
+            return "Failed to load profile".into_response();
+        };
+    let adverts = adverts.map(|advert| {
+        let views: Vec<(i64, i64)> = daily_views
+            .iter()
+            .filter(|(advert_id, _, _)| *advert_id == advert.id)
+            .map(|&(_, day, views)| (day, views))
+            .collect();
+        let chart = view_stats::daily_chart("Views per day", &views, last_day);
+        (advert, chart)
+    });
 
     let template = ProfilePageTemplate {
         adverts,
-        total_pages,
-        page,
         logged_in: true,
     };
     let reply_html = template.render().unwrap();
@@ -0,0 +1,343 @@
+use std::net::SocketAddr;
+
+use askama::Template;
+use askama_axum::IntoResponse;
+use axum::{
+    extract::{ConnectInfo, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{Html, Redirect, Response},
+    Form,
+};
+use axum_csrf::CsrfToken;
+use axum_login::{AuthSession, AuthnBackend};
+use password_auth::verify_password;
+use serde::Deserialize;
+use tokio::task;
+
+use crate::{
+    auth::AuthBackend,
+    db,
+    models::UserSettings,
+    throttle::{self, ThrottleKeys},
+    AppState,
+};
+
+const DISPLAY_NAME_MAX_LEN: usize = 64;
+const EMAIL_MAX_LEN: usize = 254;
+const CONTACT_PHONE_MAX_LEN: usize = 32;
+
+const SAVED_INFO: &str = "info";
+const SAVED_PASSWORD: &str = "password";
+
+#[derive(Template)]
+#[template(path = "settings.html")]
+pub struct SettingsPageTemplate<'a> {
+    csrf_token: &'a str,
+    settings: UserSettings,
+    notice: Option<&'a str>,
+    error: Option<&'a str>,
+    logged_in: bool,
+}
+
+#[derive(Deserialize)]
+pub struct SettingsPageParams {
+    saved: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct SettingsInfoForm {
+    csrf_token: String,
+    display_name: String,
+    email: String,
+    contact_phone: String,
+}
+
+#[derive(Deserialize)]
+pub struct SettingsPasswordForm {
+    csrf_token: String,
+    old_password: String,
+    new_password: String,
+    new_password_confirm: String,
+}
+
+#[derive(Deserialize)]
+pub struct SettingsDeleteForm {
+    csrf_token: String,
+    password: String,
+}
+
+fn render_settings(
+    token: CsrfToken,
+    settings: UserSettings,
+    notice: Option<&str>,
+    error: Option<&str>,
+) -> Response {
+    let csrf_token = if let Ok(csrf_token) = token.authenticity_token() {
+        csrf_token
+    } else {
+        return "Failed to get csrf token".into_response();
+    };
+    let template = SettingsPageTemplate {
+        csrf_token: &csrf_token,
+        settings,
+        notice,
+        error,
+        logged_in: true,
+    };
+    let reply_html = template.render().unwrap();
+    let status = if error.is_some() {
+        StatusCode::BAD_REQUEST
+    } else {
+        StatusCode::OK
+    };
+    (status, token, Html(reply_html)).into_response()
+}
+
+async fn check_password(password: String, password_hash: String) -> bool {
+    task::spawn_blocking(move || verify_password(&password, &password_hash).is_ok())
+        .await
+        .unwrap_or(false)
+}
+
+/// Checks the current password under the login throttle, so a stolen session can't be
+/// used to guess it. Returns the error to show otherwise.
+async fn check_current_password(
+    state: &AppState,
+    keys: &ThrottleKeys,
+    password: String,
+    password_hash: String,
+    wrong_error: &str,
+) -> Result<(), String> {
+    let db = &state.db;
+    match throttle::locked_until(db, keys).await {
+        Ok(Some(locked_until)) => {
+            return Err(format!(
+                "Too many wrong passwords. {}",
+                throttle::wait_message(locked_until)
+            ))
+        }
+        Ok(None) => {}
+        Err(_) => return Err("Failed to check password".to_string()),
+    }
+    if check_password(password, password_hash).await {
+        throttle::reset(db, keys).await;
+        Ok(())
+    } else {
+        throttle::record_failure(db, keys).await;
+        Err(wrong_error.to_string())
+    }
+}
+
+fn optional_field(value: &str) -> Option<String> {
+    let value = value.trim();
+    if value.is_empty() {
+        None
+    } else {
+        Some(value.to_string())
+    }
+}
+
+fn validate_settings(form: &SettingsInfoForm) -> Result<UserSettings, &'static str> {
+    let settings = UserSettings {
+        display_name: optional_field(&form.display_name),
+        email: optional_field(&form.email),
+        contact_phone: optional_field(&form.contact_phone),
+    };
+
+    if let Some(display_name) = &settings.display_name {
+        if display_name.chars().count() > DISPLAY_NAME_MAX_LEN {
+            return Err("Display name is too long");
+        }
+    }
+    if let Some(email) = &settings.email {
+        let valid = email.len() <= EMAIL_MAX_LEN
+            && !email.contains(char::is_whitespace)
+            && email
+                .split_once('@')
+                .map(|(local, domain)| !local.is_empty() && domain.contains('.'))
+                .unwrap_or(false);
+        if !valid {
+            return Err("Email address is not valid");
+        }
+    }
+    if let Some(contact_phone) = &settings.contact_phone {
+        let valid = contact_phone.len() <= CONTACT_PHONE_MAX_LEN
+            && contact_phone.chars().any(|c| c.is_ascii_digit())
+            && contact_phone
+                .chars()
+                .all(|c| c.is_ascii_digit() || " +-()".contains(c));
+        if !valid {
+            return Err("Contact phone is not valid");
+        }
+    }
+    Ok(settings)
+}
+
+pub async fn settings_page(
+    State(state): State<AppState>,
+    token: CsrfToken,
+    auth_session: AuthSession<AuthBackend>,
+    Query(params): Query<SettingsPageParams>,
+) -> impl IntoResponse {
+    let user = if let Some(user) = auth_session.user {
+        user
+    } else {
+        return "User not found".into_response();
+    };
+
+    let db = &state.db;
+    let settings = if let Ok(settings) = db::get_user_settings(db, user.id).await {
+        settings
+    } else {
+        return "Failed to load settings".into_response();
+    };
+
+    let notice = match params.saved.as_deref() {
+        Some(SAVED_INFO) => Some("Account details saved"),
+        Some(SAVED_PASSWORD) => Some("Password changed"),
+        _ => None,
+    };
+    render_settings(token, settings, notice, None)
+}
+
+pub async fn settings_edit(
+    State(state): State<AppState>,
+    token: CsrfToken,
+    auth_session: AuthSession<AuthBackend>,
+    Form(form): Form<SettingsInfoForm>,
+) -> impl IntoResponse {
+    if token.verify(&form.csrf_token).is_err() {
+        return "Failed to verify csrf".into_response();
+    }
+
+    let user = if let Some(user) = auth_session.user {
+        user
+    } else {
+        return "User not found".into_response();
+    };
+
+    let settings = match validate_settings(&form) {
+        Ok(settings) => settings,
+        Err(error) => {
+            let settings = UserSettings {
+                display_name: Some(form.display_name),
+                email: Some(form.email),
+                contact_phone: Some(form.contact_phone),
+            };
+            return render_settings(token, settings, None, Some(error));
+        }
+    };
+
+    let db = &state.db;
+    if db::update_user_settings(db, user.id, &settings)
+        .await
+        .is_err()
+    {
+        return "Failed to save settings".into_response();
+    }
+    Redirect::to(&format!("/profile/settings?saved={}", SAVED_INFO)).into_response()
+}
+
+pub async fn settings_password(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    token: CsrfToken,
+    mut auth_session: AuthSession<AuthBackend>,
+    Form(form): Form<SettingsPasswordForm>,
+) -> impl IntoResponse {
+    if token.verify(&form.csrf_token).is_err() {
+        return "Failed to verify csrf".into_response();
+    }
+
+    let user = if let Some(user) = auth_session.user.clone() {
+        user
+    } else {
+        return "User not found".into_response();
+    };
+
+    let throttle_keys = throttle::keys(state.config.client_ip(&addr, &headers), &user.username);
+    let result = if form.new_password.is_empty() {
+        Err("New password must not be empty".to_string())
+    } else if form.new_password != form.new_password_confirm {
+        Err("New passwords do not match".to_string())
+    } else {
+        check_current_password(
+            &state,
+            &throttle_keys,
+            form.old_password,
+            user.password_hash.clone(),
+            "Current password is wrong",
+        )
+        .await
+    };
+
+    if let Err(error) = result {
+        let db = &state.db;
+        let settings = db::get_user_settings(db, user.id).await.unwrap_or_default();
+        return render_settings(token, settings, None, Some(&error));
+    }
+
+    let db = &state.db;
+    if db::update_user_password(db, user.id, &form.new_password)
+        .await
+        .is_err()
+    {
+        return "Failed to change password".into_response();
+    }
+
+    // Session is bound to the password hash, so log in again with the updated user
+    match auth_session.backend.get_user(&user.id).await {
+        Ok(Some(user)) => {
+            if auth_session.login(&user).await.is_err() {
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+        }
+        _ => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+    Redirect::to(&format!("/profile/settings?saved={}", SAVED_PASSWORD)).into_response()
+}
+
+pub async fn settings_delete(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    token: CsrfToken,
+    mut auth_session: AuthSession<AuthBackend>,
+    Form(form): Form<SettingsDeleteForm>,
+) -> impl IntoResponse {
+    if token.verify(&form.csrf_token).is_err() {
+        return "Failed to verify csrf".into_response();
+    }
+
+    let user = if let Some(user) = auth_session.user.clone() {
+        user
+    } else {
+        return "User not found".into_response();
+    };
+
+    let throttle_keys = throttle::keys(state.config.client_ip(&addr, &headers), &user.username);
+    if let Err(error) = check_current_password(
+        &state,
+        &throttle_keys,
+        form.password,
+        user.password_hash.clone(),
+        "Password is wrong",
+    )
+    .await
+    {
+        let db = &state.db;
+        let settings = db::get_user_settings(db, user.id).await.unwrap_or_default();
+        return render_settings(token, settings, None, Some(&error));
+    }
+
+    let db = &state.db;
+    if db::delete_user(db, user.id).await.is_err() {
+        return "Failed to delete account".into_response();
+    }
+
+    if auth_session.logout().await.is_err() {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+    Redirect::to("/").into_response()
+}
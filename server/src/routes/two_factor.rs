@@ -0,0 +1,400 @@
+use std::net::SocketAddr;
+
+use askama::Template;
+use askama_axum::IntoResponse;
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{HeaderMap, StatusCode},
+    middleware::Next,
+    response::{Html, Redirect, Response},
+    Form,
+};
+use axum_csrf::CsrfToken;
+use axum_login::{AuthSession, AuthnBackend, AuthzBackend};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Sqlite};
+use tower_sessions::Session;
+use tracing::error;
+
+use super::auth::finish_login;
+use crate::{auth::AuthBackend, auth_models::User, db, metrics, throttle, totp, AppState};
+
+const PENDING_SECRET_KEY: &str = "totp_pending_secret";
+const PENDING_LOGIN_KEY: &str = "totp_pending_login";
+/// Time to enter the second factor after the password was accepted
+const PENDING_LOGIN_TTL_SECS: i64 = 5 * 60;
+
+/// Login which passed the password check and waits for the second factor
+#[derive(Serialize, Deserialize)]
+pub struct PendingLogin {
+    pub user_id: i64,
+    pub next: Option<String>,
+    pub started: i64,
+}
+
+pub async fn start_pending_login(
+    session: &Session,
+    user_id: i64,
+    next: Option<String>,
+) -> Result<(), ()> {
+    let pending = PendingLogin {
+        user_id,
+        next,
+        started: db::now(),
+    };
+    session
+        .insert(PENDING_LOGIN_KEY, pending)
+        .await
+        .map_err(|e| {
+            error!(error = %e, "Failed to save pending login");
+        })
+}
+
+async fn pending_login(session: &Session) -> Option<PendingLogin> {
+    let pending: PendingLogin = session.get(PENDING_LOGIN_KEY).await.ok()??;
+    if db::now() - pending.started > PENDING_LOGIN_TTL_SECS {
+        let _ = session.remove_value(PENDING_LOGIN_KEY).await;
+        return None;
+    }
+    Some(pending)
+}
+
+/// Accepts either a current totp code or an unused recovery code
+async fn check_second_factor(db: &Pool<Sqlite>, user: &User, code: &str) -> Result<bool, ()> {
+    let secret = if let Some(secret) = &user.totp_secret {
+        secret
+    } else {
+        return Ok(false);
+    };
+    if let Some(step) = totp::verify(secret, code, db::now(), user.totp_last_step) {
+        return db::use_totp_step(db, user.id, step).await;
+    }
+    db::use_recovery_code(db, user.id, &totp::hash_recovery_code(code)).await
+}
+
+async fn is_admin(auth_session: &AuthSession<AuthBackend>, user: &User) -> bool {
+    auth_session
+        .backend
+        .get_all_permissions(user)
+        .await
+        .map(|permissions| {
+            permissions
+                .iter()
+                .any(|permission| permission.name.starts_with("admin."))
+        })
+        .unwrap_or(false)
+}
+
+/// Sends admins without two-factor authentication to enrolment when it is mandatory
+pub async fn require_admin_two_factor(
+    State(state): State<AppState>,
+    auth_session: AuthSession<AuthBackend>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if state.config.require_admin_2fa {
+        if let Some(user) = &auth_session.user {
+            let path = request.uri().path();
+            let exempt = path.starts_with("/profile/2fa") || path == "/logout";
+            if user.totp_secret.is_none() && !exempt && is_admin(&auth_session, user).await {
+                return Redirect::to("/profile/2fa").into_response();
+            }
+        }
+    }
+    next.run(request).await
+}
+
+#[derive(Template, Default)]
+#[template(path = "two_factor.html")]
+pub struct TwoFactorPageTemplate<'a> {
+    csrf_token: &'a str,
+    enabled: bool,
+    mandatory: bool,
+    secret: Option<String>,
+    qr_code: Option<String>,
+    recovery_codes: Vec<String>,
+    remaining_recovery_codes: i64,
+    error: Option<&'a str>,
+    logged_in: bool,
+}
+
+#[derive(Deserialize)]
+pub struct TwoFactorForm {
+    csrf_token: String,
+    code: String,
+}
+
+async fn render_two_factor_page(
+    state: &AppState,
+    token: CsrfToken,
+    auth_session: &AuthSession<AuthBackend>,
+    session: &Session,
+    user: &User,
+    recovery_codes: Vec<String>,
+    error: Option<&str>,
+) -> Response {
+    let csrf_token = if let Ok(csrf_token) = token.authenticity_token() {
+        csrf_token
+    } else {
+        return "Failed to get csrf token".into_response();
+    };
+
+    let mut template = TwoFactorPageTemplate {
+        csrf_token: &csrf_token,
+        enabled: user.totp_secret.is_some(),
+        mandatory: state.config.require_admin_2fa && is_admin(auth_session, user).await,
+        recovery_codes,
+        error,
+        logged_in: true,
+        ..Default::default()
+    };
+
+    if template.enabled {
+        let db = &state.db;
+        template.remaining_recovery_codes =
+            db::count_recovery_codes(db, user.id).await.unwrap_or(0);
+    } else {
+        let secret = match session.get::<String>(PENDING_SECRET_KEY).await {
+            Ok(Some(secret)) => secret,
+            Ok(None) => {
+                let secret = totp::generate_secret();
+                if session.insert(PENDING_SECRET_KEY, &secret).await.is_err() {
+                    return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+                }
+                secret
+            }
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        };
+        template.qr_code = totp::qr_code_svg(&totp::otpauth_uri(&user.username, &secret));
+        template.secret = Some(secret);
+    }
+
+    let reply_html = template.render().unwrap();
+    let status = if error.is_some() {
+        StatusCode::BAD_REQUEST
+    } else {
+        StatusCode::OK
+    };
+    (status, token, Html(reply_html)).into_response()
+}
+
+pub async fn two_factor_page(
+    State(state): State<AppState>,
+    token: CsrfToken,
+    auth_session: AuthSession<AuthBackend>,
+    session: Session,
+) -> impl IntoResponse {
+    let user = if let Some(user) = auth_session.user.clone() {
+        user
+    } else {
+        return "User not found".into_response();
+    };
+    render_two_factor_page(&state, token, &auth_session, &session, &user, vec![], None).await
+}
+
+pub async fn two_factor_enable(
+    State(state): State<AppState>,
+    token: CsrfToken,
+    auth_session: AuthSession<AuthBackend>,
+    session: Session,
+    Form(form): Form<TwoFactorForm>,
+) -> impl IntoResponse {
+    if token.verify(&form.csrf_token).is_err() {
+        return "Failed to verify csrf".into_response();
+    }
+
+    let user = if let Some(user) = auth_session.user.clone() {
+        user
+    } else {
+        return "User not found".into_response();
+    };
+    if user.totp_secret.is_some() {
+        return Redirect::to("/profile/2fa").into_response();
+    }
+
+    let secret = if let Ok(Some(secret)) = session.get::<String>(PENDING_SECRET_KEY).await {
+        secret
+    } else {
+        return Redirect::to("/profile/2fa").into_response();
+    };
+    let step = if let Some(step) = totp::verify(&secret, &form.code, db::now(), None) {
+        step
+    } else {
+        return render_two_factor_page(
+            &state,
+            token,
+            &auth_session,
+            &session,
+            &user,
+            vec![],
+            Some("Code is wrong, check the time on your device"),
+        )
+        .await;
+    };
+
+    let recovery_codes = totp::generate_recovery_codes();
+    let recovery_code_hashes: Vec<String> = recovery_codes
+        .iter()
+        .map(|code| totp::hash_recovery_code(code))
+        .collect();
+    let db = &state.db;
+    if db::enable_totp(db, user.id, &secret, &recovery_code_hashes, step)
+        .await
+        .is_err()
+    {
+        return "Failed to enable two-factor authentication".into_response();
+    }
+    let _ = session.remove_value(PENDING_SECRET_KEY).await;
+
+    // Recovery codes are shown only once, right after enrolment
+    let user = match auth_session.backend.get_user(&user.id).await {
+        Ok(Some(user)) => user,
+        _ => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+    render_two_factor_page(
+        &state,
+        token,
+        &auth_session,
+        &session,
+        &user,
+        recovery_codes,
+        None,
+    )
+    .await
+}
+
+pub async fn two_factor_disable(
+    State(state): State<AppState>,
+    token: CsrfToken,
+    auth_session: AuthSession<AuthBackend>,
+    session: Session,
+    Form(form): Form<TwoFactorForm>,
+) -> impl IntoResponse {
+    if token.verify(&form.csrf_token).is_err() {
+        return "Failed to verify csrf".into_response();
+    }
+
+    let user = if let Some(user) = auth_session.user.clone() {
+        user
+    } else {
+        return "User not found".into_response();
+    };
+
+    let error = if state.config.require_admin_2fa && is_admin(&auth_session, &user).await {
+        Some("Two-factor authentication is mandatory for your account")
+    } else {
+        let db = &state.db;
+        match check_second_factor(db, &user, &form.code).await {
+            Ok(true) => {
+                if db::disable_totp(db, user.id).await.is_err() {
+                    return "Failed to disable two-factor authentication".into_response();
+                }
+                None
+            }
+            Ok(false) => Some("Code is wrong"),
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    };
+
+    if let Some(error) = error {
+        return render_two_factor_page(
+            &state,
+            token,
+            &auth_session,
+            &session,
+            &user,
+            vec![],
+            Some(error),
+        )
+        .await;
+    }
+    Redirect::to("/profile/2fa").into_response()
+}
+
+#[derive(Template, Default)]
+#[template(path = "login_2fa.html")]
+pub struct LoginTwoFactorTemplate<'a> {
+    csrf_token: &'a str,
+    error: Option<&'a str>,
+    logged_in: bool,
+}
+
+fn render_login_two_factor(token: CsrfToken, status: StatusCode, error: Option<&str>) -> Response {
+    let csrf_token = if let Ok(csrf_token) = token.authenticity_token() {
+        csrf_token
+    } else {
+        return "Failed to get csrf token".into_response();
+    };
+    let template = LoginTwoFactorTemplate {
+        csrf_token: &csrf_token,
+        error,
+        logged_in: false,
+    };
+    let reply_html = template.render().unwrap();
+    (status, token, Html(reply_html)).into_response()
+}
+
+pub async fn login_two_factor_form(token: CsrfToken, session: Session) -> impl IntoResponse {
+    if pending_login(&session).await.is_none() {
+        return Redirect::to("/login").into_response();
+    }
+    render_login_two_factor(token, StatusCode::OK, None)
+}
+
+pub async fn login_two_factor(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    token: CsrfToken,
+    mut auth_session: AuthSession<AuthBackend>,
+    session: Session,
+    Form(form): Form<TwoFactorForm>,
+) -> impl IntoResponse {
+    if token.verify(&form.csrf_token).is_err() {
+        return "Failed to verify csrf".into_response();
+    }
+
+    let pending = if let Some(pending) = pending_login(&session).await {
+        pending
+    } else {
+        return Redirect::to("/login").into_response();
+    };
+    let user = match auth_session.backend.get_user(&pending.user_id).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return Redirect::to("/login").into_response(),
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+
+    let client_ip = state.config.client_ip(&addr, &headers);
+    let throttle_keys = throttle::keys(client_ip, &user.username);
+    let db = &state.db;
+    match throttle::locked_until(db, &throttle_keys).await {
+        Ok(Some(_)) => {
+            state.metrics.login(metrics::LOGIN_THROTTLED);
+            let _ = session.remove_value(PENDING_LOGIN_KEY).await;
+            return Redirect::to("/login").into_response();
+        }
+        Ok(None) => {}
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+
+    match check_second_factor(db, &user, &form.code).await {
+        Ok(true) => {}
+        Ok(false) => {
+            state.metrics.login(metrics::LOGIN_FAILURE);
+            throttle::record_failure(db, &throttle_keys).await;
+            return render_login_two_factor(token, StatusCode::UNAUTHORIZED, Some("Code is wrong"));
+        }
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+
+    let _ = session.remove_value(PENDING_LOGIN_KEY).await;
+    finish_login(
+        &state,
+        &mut auth_session,
+        &user,
+        &throttle_keys,
+        pending.next.as_deref(),
+    )
+    .await
+}
@@ -0,0 +1,144 @@
+//! Login throttling: failed attempts are counted per client IP, per username from that IP
+//! and per username. After a few free attempts the key gets locked with exponential backoff.
+//! Username locks are capped at a minute, so guessing from many IPs is slowed down to a
+//! crawl while anyone trying to lock out the account owner only delays them a little.
+
+use std::net::IpAddr;
+
+use sqlx::{Pool, Sqlite};
+
+use crate::db;
+
+pub const IP_KIND: &str = "ip";
+pub const USERNAME_IP_KIND: &str = "user_ip";
+pub const USERNAME_KIND: &str = "user";
+
+pub type ThrottleKeys = [(&'static str, String); 3];
+
+/// How a kind of key is locked
+struct Policy {
+    free_attempts: i64,
+    base_lockout_secs: i64,
+    max_lockout_secs: i64,
+}
+
+const IP_POLICY: Policy = Policy {
+    free_attempts: 20,
+    base_lockout_secs: 30,
+    max_lockout_secs: 60 * 60,
+};
+const USERNAME_IP_POLICY: Policy = Policy {
+    free_attempts: 5,
+    base_lockout_secs: 30,
+    max_lockout_secs: 60 * 60,
+};
+const USERNAME_POLICY: Policy = Policy {
+    free_attempts: 10,
+    base_lockout_secs: 1,
+    max_lockout_secs: 60,
+};
+
+fn policy(kind: &str) -> &'static Policy {
+    match kind {
+        IP_KIND => &IP_POLICY,
+        USERNAME_KIND => &USERNAME_POLICY,
+        _ => &USERNAME_IP_POLICY,
+    }
+}
+
+/// Lockout duration after `failures` failed attempts, doubled for every attempt over the free ones
+fn lockout_secs(kind: &str, failures: i64) -> Option<i64> {
+    let policy = policy(kind);
+    let over = failures - policy.free_attempts;
+    if over <= 0 {
+        return None;
+    }
+    let secs = policy
+        .base_lockout_secs
+        .saturating_mul(1 << (over - 1).min(32));
+    Some(secs.min(policy.max_lockout_secs))
+}
+
+pub fn keys(ip: IpAddr, username: &str) -> ThrottleKeys {
+    [
+        (IP_KIND, ip.to_string()),
+        (USERNAME_IP_KIND, format!("{} {}", ip, username)),
+        (USERNAME_KIND, username.to_string()),
+    ]
+}
+
+/// Tells how long to wait until `locked_until`
+pub fn wait_message(locked_until: i64) -> String {
+    let secs = (locked_until - db::now()).max(1);
+    if secs < 60 {
+        format!("Try again in {} second(s).", secs)
+    } else {
+        format!("Try again in {} minute(s).", (secs + 59) / 60)
+    }
+}
+
+/// Returns the latest lock expiration time if any of the keys is locked
+pub async fn locked_until(db: &Pool<Sqlite>, keys: &ThrottleKeys) -> Result<Option<i64>, ()> {
+    let mut locked_until = None;
+    for (kind, value) in keys {
+        if let Some(until) = db::get_login_lock(db, kind, value).await? {
+            locked_until = locked_until.max(Some(until));
+        }
+    }
+    Ok(locked_until)
+}
+
+pub async fn record_failure(db: &Pool<Sqlite>, keys: &ThrottleKeys) {
+    for (kind, value) in keys {
+        if let Ok(failures) = db::record_login_failure(db, kind, value).await {
+            if let Some(lockout_secs) = lockout_secs(kind, failures) {
+                let _ = db::lock_login(db, kind, value, db::now() + lockout_secs).await;
+            }
+        }
+    }
+}
+
+/// Clears the username counters after a successful login. The IP budget is kept, or an
+/// attacker could reset it by logging into their own account between guesses.
+pub async fn reset(db: &Pool<Sqlite>, keys: &ThrottleKeys) {
+    for (kind, value) in keys {
+        if *kind != IP_KIND {
+            let _ = db::reset_login_attempts(db, kind, value).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{lockout_secs, IP_KIND, USERNAME_IP_KIND, USERNAME_KIND};
+
+    #[test]
+    fn free_attempts_are_not_locked() {
+        assert_eq!(lockout_secs(IP_KIND, 1), None);
+        assert_eq!(lockout_secs(IP_KIND, 20), None);
+        assert_eq!(lockout_secs(USERNAME_IP_KIND, 5), None);
+        assert_eq!(lockout_secs(USERNAME_KIND, 10), None);
+    }
+
+    #[test]
+    fn lockout_doubles_with_every_failure() {
+        assert_eq!(lockout_secs(USERNAME_IP_KIND, 6), Some(30));
+        assert_eq!(lockout_secs(USERNAME_IP_KIND, 7), Some(60));
+        assert_eq!(lockout_secs(USERNAME_IP_KIND, 8), Some(120));
+        assert_eq!(lockout_secs(IP_KIND, 21), Some(30));
+        assert_eq!(lockout_secs(IP_KIND, 22), Some(60));
+        assert_eq!(lockout_secs(USERNAME_KIND, 11), Some(1));
+        assert_eq!(lockout_secs(USERNAME_KIND, 12), Some(2));
+    }
+
+    #[test]
+    fn lockout_is_capped() {
+        assert_eq!(lockout_secs(USERNAME_IP_KIND, 12), Some(1920));
+        assert_eq!(lockout_secs(USERNAME_IP_KIND, 13), Some(60 * 60));
+        assert_eq!(lockout_secs(IP_KIND, 1000), Some(60 * 60));
+        assert_eq!(lockout_secs(USERNAME_IP_KIND, i64::MAX), Some(60 * 60));
+        // Anyone can fail logins of any username, its owner is never locked out for long
+        assert_eq!(lockout_secs(USERNAME_KIND, 17), Some(60));
+        assert_eq!(lockout_secs(USERNAME_KIND, 1000), Some(60));
+    }
+}
@@ -0,0 +1,113 @@
+//! Advert view statistics: views are recorded once per viewer, aggregated into daily
+//! counts in the background and shown to sellers as svg bar charts. Admin stats reuse the charts.
+
+use std::{fmt::Write, net::IpAddr, sync::OnceLock};
+
+use chrono::DateTime;
+use data_encoding::HEXLOWER;
+use ring::{hmac, rand::SystemRandom};
+
+use crate::db::SECS_PER_DAY;
+
+/// Raw views older than this are pruned, a user viewing an advert again after that counts again
+pub const VIEW_RETENTION_DAYS: i64 = 7;
+/// Days shown on the seller charts
+pub const CHART_DAYS: i64 = 30;
+
+/// Bytes of the hmac kept in anonymous viewer keys
+const VIEWER_KEY_LEN: usize = 16;
+
+const BOT_MARKERS: [&str; 7] = [
+    "bot",
+    "crawl",
+    "spider",
+    "slurp",
+    "preview",
+    "facebookexternalhit",
+    "headless",
+];
+
+const CHART_BAR_WIDTH: i64 = 8;
+const CHART_HEIGHT: i64 = 60;
+const CHART_LABEL_HEIGHT: i64 = 14;
+
+/// Crawlers and link previews don't count as views, neither do clients without user agent
+pub fn is_bot(user_agent: Option<&str>) -> bool {
+    match user_agent {
+        Some(user_agent) if !user_agent.trim().is_empty() => {
+            let user_agent = user_agent.to_lowercase();
+            BOT_MARKERS.iter().any(|marker| user_agent.contains(marker))
+        }
+        _ => true,
+    }
+}
+
+/// Secret of anonymous viewer keys, a new one after a restart lets a visitor count once more
+fn viewer_secret() -> &'static hmac::Key {
+    static SECRET: OnceLock<hmac::Key> = OnceLock::new();
+    SECRET.get_or_init(|| {
+        hmac::Key::generate(hmac::HMAC_SHA256, &SystemRandom::new())
+            .expect("System random generator failed")
+    })
+}
+
+/// Identifies the viewer in view records. Users are keyed by id, anonymous visitors by
+/// their address and user agent for the day, so nothing is stored in sessions for them
+/// and clients dropping cookies still count once a day.
+pub fn viewer_key(user_id: Option<i64>, ip: IpAddr, user_agent: &str, timestamp: i64) -> String {
+    if let Some(user_id) = user_id {
+        return format!("user-{}", user_id);
+    }
+    let visitor = format!("{} {} {}", ip, day(timestamp), user_agent);
+    let tag = hmac::sign(viewer_secret(), visitor.as_bytes());
+    HEXLOWER.encode(&tag.as_ref()[..VIEWER_KEY_LEN])
+}
+
+pub fn day(timestamp: i64) -> i64 {
+    timestamp.div_euclid(SECS_PER_DAY)
+}
+
+fn format_day(day: i64) -> String {
+    DateTime::from_timestamp(day * SECS_PER_DAY, 0)
+        .map(|datetime| datetime.format("%m-%d").to_string())
+        .unwrap_or_default()
+}
+
+/// Bar chart for `CHART_DAYS` days up to `last_day`, `counts` are (day, count) pairs
+pub fn daily_chart(title: &str, counts: &[(i64, i64)], last_day: i64) -> String {
+    let first_day = last_day - CHART_DAYS + 1;
+    let mut daily = [0i64; CHART_DAYS as usize];
+    for &(day, count) in counts {
+        if (first_day..=last_day).contains(&day) {
+            daily[(day - first_day) as usize] = count;
+        }
+    }
+    let max = daily.iter().copied().max().unwrap_or(0).max(1);
+
+    let width = CHART_DAYS * CHART_BAR_WIDTH;
+    let height = CHART_HEIGHT + CHART_LABEL_HEIGHT;
+    let mut svg = format!(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="{height}" viewBox="0 0 {width} {height}" role="img"><title>{title}, max {max}</title>"#
+    );
+    for (i, count) in daily.iter().enumerate() {
+        let bar_height = count * CHART_HEIGHT / max;
+        let _ = write!(
+            svg,
+            r##"<rect x="{}" y="{}" width="{}" height="{}" fill="#4a7"><title>{}: {}</title></rect>"##,
+            i as i64 * CHART_BAR_WIDTH,
+            CHART_HEIGHT - bar_height,
+            CHART_BAR_WIDTH - 1,
+            bar_height,
+            format_day(first_day + i as i64),
+            count
+        );
+    }
+    let label_y = height - 2;
+    let _ = write!(
+        svg,
+        r#"<text x="0" y="{label_y}" font-size="10">{}</text><text x="{width}" y="{label_y}" font-size="10" text-anchor="end">{}</text></svg>"#,
+        format_day(first_day),
+        format_day(last_day)
+    );
+    svg
+}